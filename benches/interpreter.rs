@@ -0,0 +1,52 @@
+//! Measures the per-instruction overhead of the interpreter loop.
+//!
+//! Run with `cargo bench`.
+
+use std::time::Instant;
+
+use bci::vm::BciVm;
+
+/// Counts down from `ITERATIONS` while calling a function in every iteration, so both the
+/// instruction dispatch and the built-in/function lookup of `CALL` are exercised.
+const PROGRAM: &str = r"
+IDENTITY:
+RETURN_VALUE
+
+MAIN:
+LOAD_VAL 100000
+WRITE_VAR 'i'
+READ_VAR 'i'
+LOAD_VAL 0
+CMP
+JE -6
+READ_VAR 'i'
+CALL IDENTITY
+DECR
+WRITE_VAR 'i'
+JMP 8
+NOP
+HALT 0
+";
+
+const RUNS: u32 = 10;
+
+fn main() {
+    let mut total_instructions = 0u64;
+    let start = Instant::now();
+
+    for _ in 0..RUNS {
+        let mut vm = BciVm::load(PROGRAM).unwrap();
+        while !vm.is_halted() {
+            vm.next_instruction().unwrap();
+            total_instructions += 1;
+        }
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "{} instructions in {:?} ({:.2} ns/instruction)",
+        total_instructions,
+        elapsed,
+        elapsed.as_nanos() as f64 / total_instructions as f64
+    );
+}
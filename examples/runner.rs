@@ -1,17 +1,28 @@
-use std::{env, fs};
+use std::{env, process};
 
 use bci::vm::BciVm;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 1 {
+    if args.len() < 2 {
         println!("An example bci file should be provided.");
+        process::exit(1);
     }
 
-    let program = fs::read_to_string(&args[1]).unwrap();
-
-    let mut vm = BciVm::load(&program).unwrap();
+    // The error mentions the path of the file
+    let mut vm = match BciVm::load_file(&args[1]) {
+        Ok(vm) => vm,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            process::exit(1);
+        }
+    };
+    // Rest of the arguments are passed to the program
+    vm.set_args(args[2..].to_vec());
     vm.run().unwrap();
 
-    println!("Process is finished with exit code: {}", vm.halt.unwrap());
+    println!(
+        "Process is finished with exit code: {}",
+        vm.exit_code().unwrap()
+    );
 }
@@ -1,50 +1,75 @@
 //! Bytecode representation
 
 use anyhow::anyhow;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufRead,
+    rc::Rc,
+};
 
 use crate::{
     lexer::Lexer,
     token::{Op, Token},
 };
 
-static ENTRY_POINT: &'static str = "MAIN";
+pub(crate) static ENTRY_POINT: &str = "MAIN";
 
-/// Representation of bytecode
-#[derive(Debug)]
-pub struct Bytecode<'a> {
+/// Number of the instruction kinds, see `Instruction::opcode`
+pub(crate) const OPCODE_COUNT: usize = Instruction::Nop.opcode() + 1;
+
+/// Representation of bytecode. The default is an empty bytecode without any functions.
+#[derive(Debug, Default)]
+pub struct Bytecode {
     /// Array of instructions from top to bottom
-    pub instructions: Vec<Instruction<'a>>,
+    pub instructions: Vec<Instruction>,
     /// Function table which maps function name to it's attributes
-    pub fn_table: HashMap<&'a str, Function<'a>>,
+    pub fn_table: HashMap<Rc<str>, Function>,
+    /// Line of every instruction in the source, starting from `1`. Instructions that are not
+    /// written in the source, such as the call of `MAIN`, are at line `0`.
+    pub source_lines: Vec<usize>,
+    /// String literals of `PUSH_STR`, each one stored once. This only deduplicates the literals in
+    /// the bytecode, the bytes are still copied onto the stack on every push.
+    pub str_pool: Vec<Rc<str>>,
 }
 
 /// Function attributes
 #[derive(Debug)]
-pub struct Function<'a> {
+pub struct Function {
     /// Name of the function
-    pub name: &'a str,
+    pub name: Rc<str>,
     /// Address(line number) of the function.
     pub ptr: usize,
+    /// Whether the function is annotated as pure, eg. `SQUARE: PURE`. A pure function has no IO
+    /// and returns the same result for the same arguments, so its calls can be memoized or folded.
+    /// The annotation is not verified.
+    pub pure: bool,
 }
 
 /// Supported instructions of the bytecode
+///
+/// Instructions own their names and strings, so the bytecode does not borrow the program. The
+/// strings are reference counted, which keeps cloning an instruction cheap. This lets the VM read
+/// an instruction by value without borrowing the bytecode.
+///
+/// The variants are numbered in the order they are declared, see `opcode`. `Nop` should stay the
+/// last one.
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub enum Instruction<'a> {
+#[repr(u8)]
+pub enum Instruction {
     /// Call a function
-    Call(&'a str),
+    Call(Rc<str>),
     /// Halt the program with an exit code
     Halt(i32),
     /// Push string onto memory
-    PushStr(&'a str),
+    PushStr(Rc<str>),
     /// Pop string from memory and discard it
     PopStr,
     /// Load a value into memory
-    LoadVal(i32),
+    LoadVal(i64),
     /// Create/modify a variable
-    WriteVar(&'a str),
+    WriteVar(Rc<str>),
     /// Read a variable from memory to memory
-    ReadVar(&'a str),
+    ReadVar(Rc<str>),
     /// Compare two values on stack
     Cmp,
     /// Compare two strings on stack
@@ -61,6 +86,8 @@ pub enum Instruction<'a> {
     Jl(i32),
     /// Add two values
     Add,
+    /// Subtract two values
+    Sub,
     /// Multiply two values
     Mul,
     /// Decrement a value
@@ -71,15 +98,122 @@ pub enum Instruction<'a> {
     RetValue,
     // Return
     Ret,
+    /// Pop a value and jmp if it is zero
+    Jz(i32),
+    /// Pop a value and jmp if it is not zero
+    Jnz(i32),
+    /// Replace a value with its sign
+    Sign,
+    /// Keep the smaller of two values
+    Min,
+    /// Keep the larger of two values
+    Max,
+    /// Replace a value with its absolute value
+    Abs,
+    /// Raise a value to the power of another
+    Pow,
+    /// Pop two strings and jmp if they are equal
+    JeStr(i32),
+    /// Pop two strings and jmp if they are not equal
+    JneStr(i32),
+    /// Create/modify a string variable
+    WriteStrVar(Rc<str>),
+    /// Read a string variable to memory
+    ReadStrVar(Rc<str>),
+    /// Pop `n` values from stack at once
+    DropN(i32),
+    /// Read a variable to memory, or the default value if it does not exist
+    ReadVarOr(Rc<str>, i64),
+    /// Logical and of the last two values
+    AndB,
+    /// Logical or of the last two values
+    OrB,
+    /// Return from a function with multiple values
+    RetValues(i32),
+    /// Halt the program if the last two values are not equal
+    AssertEq,
+    /// Jump if the previous comparison is greater or equal
+    Jge(i32),
+    /// Jump if the previous comparison is less or equal
+    Jle(i32),
+    /// Print the last value
+    Print,
+    /// Add an immediate value to the last value
+    AddImm(i64),
+    /// Subtract an immediate value from the last value
+    SubImm(i64),
+    /// Normalize the last value to `1` or `0`
+    Bool,
+    /// Swap the values of two variables
+    SwapVar(Rc<str>, Rc<str>),
+    /// Push the number of values on stack
+    StackSize,
+    /// Jump if the last value equals an immediate value
+    JeqImm(i64, i32),
+    /// Start a region whose runtime errors jump to a handler
+    TryBegin(i32),
+    /// End the last try region
+    TryEnd,
+    /// Write the value on top of stack to a variable without popping it
+    TeeVar(Rc<str>),
+    /// Halt the program with the value that `MAIN` returned with `RETURN_VALUE`, or `0`. It is
+    /// injected after the call of `MAIN` and cannot be written in a program.
+    Exit,
+    /// Compare two numbers or two strings according to their kinds
+    Cmp3,
+    /// Push a string of the constant pool onto memory
+    LoadConstStr(usize),
+    /// Push a copy of a value that is deeper in the stack
+    Pick(i32),
+    /// Pop a selector and jump to the offset of its case, or to the default offset
+    Switch(Rc<[i32]>),
+    /// Call the function at a location relative to the current instruction
+    CallRel(i32),
+    /// Push the return value of the last call along with a flag of whether it had one
+    LoadRet,
+    /// Pop a value and create/modify a variable in the frame of the caller
+    WriteUpvar(Rc<str>),
+    /// Push a variable of the frame of the caller
+    ReadUpvar(Rc<str>),
+    /// Compare two strings ignoring ASCII case
+    CmpStrCi,
     /// Pass
     Nop,
 }
 
+/// Pattern of the instructions with a single relative jump offset, which is bound to `$offset`.
+/// `jump_offset` and `jump_offset_mut` share it, so that they agree on what a jump is.
+macro_rules! jump_with_offset {
+    ($offset:ident) => {
+        Instruction::Jmp($offset)
+            | Instruction::Je($offset)
+            | Instruction::Jne($offset)
+            | Instruction::Jg($offset)
+            | Instruction::Jl($offset)
+            | Instruction::Jz($offset)
+            | Instruction::Jnz($offset)
+            | Instruction::JeStr($offset)
+            | Instruction::JneStr($offset)
+            | Instruction::Jge($offset)
+            | Instruction::Jle($offset)
+            | Instruction::JeqImm(_, $offset)
+            | Instruction::TryBegin($offset)
+            | Instruction::CallRel($offset)
+    };
+}
+
 macro_rules! impl_parse_fn {
     ($fn_name:ident;$instruction:ident($token_ident:ident)) => {
-        fn $fn_name(&mut self) -> ParseRes<'a> {
-            match self.lexer.next_token()? {
-                Some(Token::$token_ident(inner_data)) => Ok(Instruction::$instruction(inner_data)),
+        fn $fn_name(&mut self) -> ParseRes {
+            match self.next_operand()? {
+                Some(Token::$token_ident(inner_data)) => match inner_data.try_into() {
+                    Ok(data) => Ok(Instruction::$instruction(data)),
+                    Err(_) => Err(anyhow!(
+                        "{} is out of range for {}",
+                        inner_data,
+                        stringify!($instruction)
+                    )),
+                },
                 token => Err(anyhow!(
                     "Expected {}, got {:?}",
                     stringify!($inner_expr),
@@ -89,79 +223,758 @@ macro_rules! impl_parse_fn {
         }
     };
 
+    ($fn_name:ident;$instruction:ident($first_ident:ident, $second_ident:ident)) => {
+        fn $fn_name(&mut self) -> ParseRes {
+            let first = match self.next_operand()? {
+                Some(Token::$first_ident(inner_data)) => inner_data.try_into().map_err(|_| {
+                    anyhow!(
+                        "{} is out of range for {}",
+                        inner_data,
+                        stringify!($instruction)
+                    )
+                })?,
+                token => {
+                    return Err(anyhow!(
+                        "Expected {}, got {:?}",
+                        stringify!($first_ident),
+                        token
+                    ))
+                }
+            };
+            let second = match self.next_operand()? {
+                Some(Token::$second_ident(inner_data)) => inner_data.try_into().map_err(|_| {
+                    anyhow!(
+                        "{} is out of range for {}",
+                        inner_data,
+                        stringify!($instruction)
+                    )
+                })?,
+                token => {
+                    return Err(anyhow!(
+                        "Expected {}, got {:?}",
+                        stringify!($second_ident),
+                        token
+                    ))
+                }
+            };
+
+            Ok(Instruction::$instruction(first, second))
+        }
+    };
+
     ($fn_name:ident;$instruction:ident) => {
-        fn $fn_name(&mut self) -> ParseRes<'a> {
+        fn $fn_name(&mut self) -> ParseRes {
             Ok(Instruction::$instruction)
         }
     };
 }
 
-impl<'a> Bytecode<'a> {
+impl Bytecode {
     fn new() -> Self {
-        // This is a small hack to properly end the program. Once the main function returns, `halt 0` will run and
-        // properly halt the program.
-        let instructions = vec![Instruction::Call(ENTRY_POINT), Instruction::Halt(0)];
+        // This is a small hack to properly end the program. Once the main function returns, `Exit` will run and
+        // properly halt the program with the return value of the main function.
+        let instructions = vec![Instruction::Call(ENTRY_POINT.into()), Instruction::Exit];
         Bytecode {
+            source_lines: vec![0; instructions.len()],
             instructions,
             fn_table: HashMap::new(),
+            str_pool: Vec::new(),
         }
     }
+
+    /// Appends an instruction that is written at `line` of the source
+    fn push(&mut self, instruction: Instruction, line: usize) {
+        self.instructions.push(instruction);
+        self.source_lines.push(line);
+    }
+
+    /// Returns the source line of the instruction at `addr` if it is written in the source
+    pub fn source_line(&self, addr: usize) -> Option<usize> {
+        self.source_lines
+            .get(addr)
+            .copied()
+            .filter(|&line| line > 0)
+    }
+
+    /// Combines the functions of `modules` into a single bytecode that starts by calling `MAIN`, so
+    /// a program can be split across files. Modules are usually parsed with
+    /// `Parser::parse_library`, and the call of `MAIN` that `Parser::parse` injects is dropped from
+    /// the modules. Jump offsets are relative, so only the function addresses and the indices of
+    /// the constant pool are rebased. Fails if a function is defined by more than one module, or
+    /// if none of them defines `MAIN`.
+    pub fn link(modules: Vec<Bytecode>) -> anyhow::Result<Bytecode> {
+        let mut linked = Bytecode::new();
+        let mut str_ids: HashMap<Rc<str>, usize> = HashMap::new();
+
+        for module in modules {
+            // Instructions that are not written in the source are the injected entry code
+            let entry_len = module
+                .source_lines
+                .iter()
+                .take_while(|&&line| line == 0)
+                .count();
+            let base = linked.instructions.len() - entry_len;
+
+            for (name, mut function) in module.fn_table {
+                if linked.fn_table.contains_key(&name) {
+                    return Err(anyhow!("Function {} is already defined.", name));
+                }
+
+                function.ptr += base;
+                linked.fn_table.insert(name, function);
+            }
+
+            // Strings that are in more than one module are stored once
+            let str_map: Vec<usize> = module
+                .str_pool
+                .into_iter()
+                .map(|s| {
+                    *str_ids.entry(Rc::clone(&s)).or_insert_with(|| {
+                        linked.str_pool.push(s);
+                        linked.str_pool.len() - 1
+                    })
+                })
+                .collect();
+
+            for (addr, mut instruction) in
+                module.instructions.into_iter().enumerate().skip(entry_len)
+            {
+                if let Instruction::LoadConstStr(index) = &mut instruction {
+                    // Out of bounds indices stay out of bounds and fail at runtime as before
+                    *index = str_map.get(*index).copied().unwrap_or(usize::MAX);
+                }
+
+                let line = module.source_lines.get(addr).copied().unwrap_or(0);
+                linked.push(instruction, line);
+            }
+        }
+
+        if !linked.fn_table.contains_key(ENTRY_POINT) {
+            return Err(anyhow!("Could not find the entry point(MAIN)."));
+        }
+
+        Ok(linked)
+    }
+
+    /// Removes the `Nop` instructions and rewrites the jump offsets and the function addresses
+    /// according to the compacted layout. A `WRITE_VAR` that is immediately followed by a
+    /// `READ_VAR` of the same variable is fused into a `TEE_VAR` as well. The optimized bytecode
+    /// behaves exactly the same.
+    pub fn optimize(mut self) -> Bytecode {
+        self.fuse_write_read_pairs();
+
+        // Maps every old address to the new one. A removed `Nop` maps to the address of the next
+        // instruction that is kept, which is where the execution would end up anyway.
+        let mut addr_map = Vec::with_capacity(self.instructions.len() + 1);
+        let mut new_addr = 0;
+        for instruction in &self.instructions {
+            addr_map.push(new_addr);
+            if *instruction != Instruction::Nop {
+                new_addr += 1;
+            }
+        }
+        addr_map.push(new_addr);
+
+        let mut instructions = Vec::with_capacity(new_addr);
+        let mut source_lines = Vec::with_capacity(new_addr);
+        for (addr, mut instruction) in self.instructions.into_iter().enumerate() {
+            if instruction == Instruction::Nop {
+                continue;
+            }
+
+            if let Some(&line) = self.source_lines.get(addr) {
+                source_lines.push(line);
+            }
+
+            for offset in instruction.jump_offsets_mut() {
+                let target = addr as i64 - *offset as i64;
+                // Jumps that are out of bounds stay out of bounds and fail at runtime as before.
+                if target >= 0 && (target as usize) < addr_map.len() {
+                    *offset = (addr_map[addr] as i64 - addr_map[target as usize] as i64) as i32;
+                }
+            }
+
+            instructions.push(instruction);
+        }
+
+        for function in self.fn_table.values_mut() {
+            function.ptr = addr_map[function.ptr];
+        }
+
+        self.instructions = instructions;
+        self.source_lines = source_lines;
+        self
+    }
+
+    /// Rewrites `WRITE_VAR 'x'` followed by `READ_VAR 'x'` as `TEE_VAR 'x'` and a `Nop`, so that
+    /// the addresses stay the same. A pair is left as is if anything jumps to the `READ_VAR`,
+    /// since the jump would skip the read otherwise.
+    fn fuse_write_read_pairs(&mut self) {
+        let mut targets = HashSet::new();
+        for (addr, instruction) in self.instructions.iter().enumerate() {
+            for offset in instruction.jump_offsets() {
+                targets.insert(addr as i64 - offset as i64);
+            }
+        }
+        targets.extend(self.fn_table.values().map(|function| function.ptr as i64));
+
+        for addr in 1..self.instructions.len() {
+            let fused = match &self.instructions[addr - 1..=addr] {
+                [Instruction::WriteVar(write), Instruction::ReadVar(read)]
+                    if write == read && !targets.contains(&(addr as i64)) =>
+                {
+                    Instruction::TeeVar(Rc::clone(write))
+                }
+                _ => continue,
+            };
+
+            self.instructions[addr - 1] = fused;
+            self.instructions[addr] = Instruction::Nop;
+        }
+    }
+
+    /// Returns the names of the functions that are defined in the program, sorted.
+    pub fn function_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.fn_table.keys().map(|name| &**name).collect();
+        names.sort_unstable();
+
+        names
+    }
+
+    /// Returns a numbered listing of the instructions, one per line. Jump instructions are
+    /// annotated with the absolute address they jump to, eg. `0007: Jmp(5)  ; -> 0002`.
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for (addr, instruction) in self.instructions.iter().enumerate() {
+            listing.push_str(&format!("{:04}: {:?}", addr, instruction));
+            let targets: Vec<String> = instruction
+                .jump_offsets()
+                .into_iter()
+                .map(|offset| format!("{:04}", addr as i64 - offset as i64))
+                .collect();
+            if !targets.is_empty() {
+                listing.push_str(&format!("  ; -> {}", targets.join(", ")));
+            }
+            listing.push('\n');
+        }
+
+        listing
+    }
+
+    /// Serializes the bytecode as JSON for external tools, eg. editors and visualizers. The
+    /// output is a single line in the following schema:
+    ///
+    /// ```text
+    /// {
+    ///   "instructions": [{"addr": 0, "op": "CALL", "args": ["MAIN"], "line": 0}, ...],
+    ///   "functions": [{"name": "MAIN", "ptr": 2}, ...],
+    ///   "str_pool": ["hello", ...]
+    /// }
+    /// ```
+    ///
+    /// `op` is the keyword of the instruction and `args` are its data in the order they are
+    /// written in the program, as numbers or strings. `line` is the source line, `0` for the
+    /// instructions that are not written in the source. Functions are sorted by name.
+    pub fn to_json(&self) -> String {
+        let instructions: Vec<String> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(addr, instruction)| {
+                format!(
+                    "{{\"addr\":{},\"op\":{},\"args\":[{}],\"line\":{}}}",
+                    addr,
+                    json_str(instruction.mnemonic()),
+                    instruction.json_args().join(","),
+                    self.source_lines.get(addr).copied().unwrap_or(0)
+                )
+            })
+            .collect();
+        let functions: Vec<String> = self
+            .function_names()
+            .into_iter()
+            .map(|name| {
+                format!(
+                    "{{\"name\":{},\"ptr\":{}}}",
+                    json_str(name),
+                    self.fn_table[name].ptr
+                )
+            })
+            .collect();
+        let str_pool: Vec<String> = self.str_pool.iter().map(|s| json_str(s)).collect();
+
+        format!(
+            "{{\"instructions\":[{}],\"functions\":[{}],\"str_pool\":[{}]}}",
+            instructions.join(","),
+            functions.join(","),
+            str_pool.join(",")
+        )
+    }
 }
 
-type ParseRes<'a> = anyhow::Result<Instruction<'a>>;
-type ParseFn<'a> = fn(&mut Parser<'a>) -> anyhow::Result<Instruction<'a>>;
+impl Instruction {
+    /// Returns the number of the instruction kind, which is below `OPCODE_COUNT`. This is cheap
+    /// enough to index the counters of the VM with on every instruction.
+    pub(crate) const fn opcode(&self) -> usize {
+        // SAFETY: `repr(u8)` puts the discriminant in the first byte of every variant
+        unsafe { *(self as *const Self as *const u8) as usize }
+    }
+
+    /// Returns the keyword of the instruction as it is written in the program, eg. `LOAD_VAL`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Call(_) => "CALL",
+            Instruction::Halt(_) => "HALT",
+            Instruction::PushStr(_) => "PUSH_STR",
+            Instruction::PopStr => "POP_STR",
+            Instruction::LoadVal(_) => "LOAD_VAL",
+            Instruction::WriteVar(_) => "WRITE_VAR",
+            Instruction::ReadVar(_) => "READ_VAR",
+            Instruction::Cmp => "CMP",
+            Instruction::CmpStr => "CMP_STR",
+            Instruction::Jmp(_) => "JMP",
+            Instruction::Je(_) => "JE",
+            Instruction::Jne(_) => "JNE",
+            Instruction::Jg(_) => "JG",
+            Instruction::Jl(_) => "JL",
+            Instruction::Add => "ADD",
+            Instruction::Sub => "SUB",
+            Instruction::Mul => "MUL",
+            Instruction::Decr => "DECR",
+            Instruction::Incr => "INCR",
+            Instruction::RetValue => "RETURN_VALUE",
+            Instruction::Ret => "RETURN",
+            Instruction::Jz(_) => "JZ",
+            Instruction::Jnz(_) => "JNZ",
+            Instruction::Sign => "SIGN",
+            Instruction::Min => "MIN",
+            Instruction::Max => "MAX",
+            Instruction::Abs => "ABS",
+            Instruction::Pow => "POW",
+            Instruction::JeStr(_) => "JE_STR",
+            Instruction::JneStr(_) => "JNE_STR",
+            Instruction::WriteStrVar(_) => "WRITE_STR_VAR",
+            Instruction::ReadStrVar(_) => "READ_STR_VAR",
+            Instruction::DropN(_) => "DROP_N",
+            Instruction::ReadVarOr(_, _) => "READ_VAR_OR",
+            Instruction::AndB => "AND_B",
+            Instruction::OrB => "OR_B",
+            Instruction::RetValues(_) => "RETURN_VALUES",
+            Instruction::AssertEq => "ASSERT_EQ",
+            Instruction::Jge(_) => "JGE",
+            Instruction::Jle(_) => "JLE",
+            Instruction::Print => "PRINT",
+            Instruction::AddImm(_) => "ADD_IMM",
+            Instruction::SubImm(_) => "SUB_IMM",
+            Instruction::Bool => "BOOL",
+            Instruction::SwapVar(_, _) => "SWAP_VAR",
+            Instruction::StackSize => "STACK_SIZE",
+            Instruction::JeqImm(_, _) => "JEQ_IMM",
+            Instruction::TryBegin(_) => "TRY_BEGIN",
+            Instruction::TryEnd => "TRY_END",
+            Instruction::TeeVar(_) => "TEE_VAR",
+            Instruction::Exit => "EXIT",
+            Instruction::Cmp3 => "CMP3",
+            Instruction::LoadConstStr(_) => "LOAD_CONST_STR",
+            Instruction::Pick(_) => "PICK",
+            Instruction::Switch(_) => "SWITCH",
+            Instruction::CallRel(_) => "CALL_REL",
+            Instruction::LoadRet => "LOAD_RET",
+            Instruction::WriteUpvar(_) => "WRITE_UPVAR",
+            Instruction::ReadUpvar(_) => "READ_UPVAR",
+            Instruction::CmpStrCi => "CMP_STR_CI",
+            Instruction::Nop => "NOP",
+        }
+    }
+
+    /// Returns the relative jump offset if this is a jump instruction. `CALL_REL` counts as a jump,
+    /// since its offset is relative as well.
+    pub fn jump_offset(&self) -> Option<i32> {
+        match self {
+            jump_with_offset!(offset) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// Returns all of the relative jump offsets. Unlike `jump_offset`, this includes the offsets of
+    /// `SWITCH`.
+    pub fn jump_offsets(&self) -> Vec<i32> {
+        match self {
+            Instruction::Switch(offsets) => offsets.to_vec(),
+            instruction => instruction.jump_offset().into_iter().collect(),
+        }
+    }
+
+    /// Same as `jump_offsets`, but the offsets can be modified.
+    pub fn jump_offsets_mut(&mut self) -> Vec<&mut i32> {
+        match self {
+            Instruction::Switch(offsets) => Rc::make_mut(offsets).iter_mut().collect(),
+            instruction => instruction.jump_offset_mut().into_iter().collect(),
+        }
+    }
+
+    /// Same as `jump_offset`, but the offset can be modified.
+    pub fn jump_offset_mut(&mut self) -> Option<&mut i32> {
+        match self {
+            jump_with_offset!(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of the instruction as JSON values, in the order they are written
+    fn json_args(&self) -> Vec<String> {
+        match self {
+            Instruction::Call(s)
+            | Instruction::PushStr(s)
+            | Instruction::WriteVar(s)
+            | Instruction::ReadVar(s)
+            | Instruction::WriteStrVar(s)
+            | Instruction::ReadStrVar(s)
+            | Instruction::TeeVar(s)
+            | Instruction::WriteUpvar(s)
+            | Instruction::ReadUpvar(s) => vec![json_str(s)],
+            Instruction::Halt(n)
+            | Instruction::Jmp(n)
+            | Instruction::Je(n)
+            | Instruction::Jne(n)
+            | Instruction::Jg(n)
+            | Instruction::Jl(n)
+            | Instruction::Jz(n)
+            | Instruction::Jnz(n)
+            | Instruction::JeStr(n)
+            | Instruction::JneStr(n)
+            | Instruction::DropN(n)
+            | Instruction::RetValues(n)
+            | Instruction::Jge(n)
+            | Instruction::Jle(n)
+            | Instruction::TryBegin(n)
+            | Instruction::Pick(n)
+            | Instruction::CallRel(n) => vec![n.to_string()],
+            Instruction::LoadVal(n) | Instruction::AddImm(n) | Instruction::SubImm(n) => {
+                vec![n.to_string()]
+            }
+            Instruction::LoadConstStr(index) => vec![index.to_string()],
+            Instruction::ReadVarOr(s, n) => vec![json_str(s), n.to_string()],
+            Instruction::SwapVar(a, b) => vec![json_str(a), json_str(b)],
+            Instruction::JeqImm(imm, offset) => vec![imm.to_string(), offset.to_string()],
+            Instruction::Switch(offsets) => offsets.iter().map(|n| n.to_string()).collect(),
+            // Listed one by one, so that a new instruction with data has to be handled above
+            Instruction::PopStr
+            | Instruction::Cmp
+            | Instruction::CmpStr
+            | Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Decr
+            | Instruction::Incr
+            | Instruction::RetValue
+            | Instruction::Ret
+            | Instruction::Sign
+            | Instruction::Min
+            | Instruction::Max
+            | Instruction::Abs
+            | Instruction::Pow
+            | Instruction::AndB
+            | Instruction::OrB
+            | Instruction::AssertEq
+            | Instruction::Print
+            | Instruction::Bool
+            | Instruction::StackSize
+            | Instruction::TryEnd
+            | Instruction::Exit
+            | Instruction::Cmp3
+            | Instruction::LoadRet
+            | Instruction::CmpStrCi
+            | Instruction::Nop => Vec::new(),
+        }
+    }
+}
+
+type ParseRes = anyhow::Result<Instruction>;
+
+/// Quotes `s` as a JSON string
+fn json_str(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if ch.is_control() => json.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+/// Whether `name` is written like an instruction keyword, eg. `LOAD_VAL`
+fn looks_like_instruction(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
 
 /// Parser to generate bytecode from text
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
-    parse_fns: HashMap<Op, ParseFn<'a>>,
+    line: usize,         // line of the last token
+    after_newline: bool, // whether the last token ended a line
+    str_pool: Vec<Rc<str>>,
+    str_ids: HashMap<Rc<str>, usize>, // index of every literal in `str_pool`
+    peeked: Option<Option<Token<'a>>>, // token that is read ahead by `peek_token`
+    constants: HashMap<String, i64>,  // constants that are defined by `CONST`
 }
 
 impl<'a> Parser<'a> {
-    /// Initialize the parser and prepare the parser functions
+    /// Initialize the parser
     ///
     /// * `program` program to parse
     pub fn new(program: &'a str) -> Self {
-        let mut parse_fns: HashMap<Op, ParseFn> = HashMap::new();
-        parse_fns.insert(Op::LoadVal, Self::parse_load_val);
-        parse_fns.insert(Op::WriteVar, Self::parse_write_var);
-        parse_fns.insert(Op::ReadVar, Self::parse_read_var);
-        parse_fns.insert(Op::Call, Self::parse_call);
-        parse_fns.insert(Op::Halt, Self::parse_halt);
-        parse_fns.insert(Op::Cmp, Self::parse_cmp);
-        parse_fns.insert(Op::CmpStr, Self::parse_cmp_str);
-        parse_fns.insert(Op::Jmp, Self::parse_jmp);
-        parse_fns.insert(Op::Je, Self::parse_je);
-        parse_fns.insert(Op::Jne, Self::parse_jne);
-        parse_fns.insert(Op::Jl, Self::parse_jl);
-        parse_fns.insert(Op::Jg, Self::parse_jg);
-        parse_fns.insert(Op::Add, Self::parse_add);
-        parse_fns.insert(Op::Mul, Self::parse_mul);
-        parse_fns.insert(Op::Decr, Self::parse_decr);
-        parse_fns.insert(Op::Incr, Self::parse_incr);
-        parse_fns.insert(Op::ReturnValue, Self::parse_ret_value);
-        parse_fns.insert(Op::Return, Self::parse_ret);
-        parse_fns.insert(Op::Nop, Self::parse_nop);
-        parse_fns.insert(Op::PushStr, Self::parse_push_str);
-        parse_fns.insert(Op::PopStr, Self::parse_pop_str);
-
         let lexer = Lexer::new(program);
 
-        Parser { lexer, parse_fns }
+        Parser {
+            lexer,
+            line: 1,
+            after_newline: false,
+            str_pool: Vec::new(),
+            str_ids: HashMap::new(),
+            peeked: None,
+            constants: HashMap::new(),
+        }
+    }
+
+    /// Get the next token from the lexer, skipping the comments
+    fn next_token(&mut self) -> anyhow::Result<Option<Token<'a>>> {
+        if let Some(token) = self.peeked.take() {
+            return Ok(token);
+        }
+
+        loop {
+            match self.lexer.next_token()? {
+                Some(Token::Comment(_)) => continue,
+                token => {
+                    if self.after_newline {
+                        self.line += 1;
+                    }
+                    self.after_newline = token == Some(Token::Newline);
+                    return Ok(token);
+                }
+            }
+        }
+    }
+
+    /// Get the next token without consuming it
+    fn peek_token(&mut self) -> anyhow::Result<&Option<Token<'a>>> {
+        if self.peeked.is_none() {
+            let token = self.next_token()?;
+            self.peeked = Some(token);
+        }
+
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    /// Get the next token as the data of an instruction. Names of constants are resolved to their
+    /// values.
+    fn next_operand(&mut self) -> anyhow::Result<Option<Token<'a>>> {
+        match self.next_token()? {
+            Some(Token::Name(name)) if self.constants.contains_key(name) => {
+                Ok(Some(Token::Number(self.constants[name])))
+            }
+            token => Ok(token),
+        }
+    }
+
+    /// Parse `CONST NAME value`, which defines a constant that can be used in place of a number
+    fn parse_const(&mut self) -> anyhow::Result<()> {
+        let name = match self.next_token()? {
+            Some(Token::Name(name)) => name,
+            token => return Err(anyhow!("Expected constant name, got {:?}", token)),
+        };
+        let value = match self.next_operand()? {
+            Some(Token::Number(value)) => value,
+            token => return Err(anyhow!("Expected Number, got {:?}", token)),
+        };
+
+        if self.constants.insert(name.to_string(), value).is_some() {
+            return Err(anyhow!("Constant {} is already defined.", name));
+        }
+
+        Ok(())
+    }
+
+    /// Parse the instruction `op` along with its data if it has any
+    fn parse_instruction(&mut self, op: Op) -> ParseRes {
+        match op {
+            Op::LoadVal => self.parse_load_val(),
+            Op::WriteVar => self.parse_write_var(),
+            Op::ReadVar => self.parse_read_var(),
+            Op::Call => self.parse_call(),
+            Op::Halt => self.parse_halt(),
+            Op::Cmp => self.parse_cmp(),
+            Op::CmpStr => self.parse_cmp_str(),
+            Op::Jmp => self.parse_jmp(),
+            Op::Je => self.parse_je(),
+            Op::Jne => self.parse_jne(),
+            Op::Jl => self.parse_jl(),
+            Op::Jg => self.parse_jg(),
+            Op::Add => self.parse_add(),
+            Op::Sub => self.parse_sub(),
+            Op::Mul => self.parse_mul(),
+            Op::Decr => self.parse_decr(),
+            Op::Incr => self.parse_incr(),
+            Op::ReturnValue => self.parse_ret_value(),
+            Op::Return => self.parse_ret(),
+            Op::Jz => self.parse_jz(),
+            Op::Jnz => self.parse_jnz(),
+            Op::Sign => self.parse_sign(),
+            Op::Min => self.parse_min(),
+            Op::Max => self.parse_max(),
+            Op::Abs => self.parse_abs(),
+            Op::Pow => self.parse_pow(),
+            Op::JeStr => self.parse_je_str(),
+            Op::JneStr => self.parse_jne_str(),
+            Op::WriteStrVar => self.parse_write_str_var(),
+            Op::ReadStrVar => self.parse_read_str_var(),
+            Op::DropN => self.parse_drop_n(),
+            Op::ReadVarOr => self.parse_read_var_or(),
+            Op::AndB => self.parse_and_b(),
+            Op::OrB => self.parse_or_b(),
+            Op::ReturnValues => self.parse_ret_values(),
+            Op::AssertEq => self.parse_assert_eq(),
+            Op::Jge => self.parse_jge(),
+            Op::Jle => self.parse_jle(),
+            Op::Print => self.parse_print(),
+            Op::AddImm => self.parse_add_imm(),
+            Op::SubImm => self.parse_sub_imm(),
+            Op::Bool => self.parse_bool(),
+            Op::SwapVar => self.parse_swap_var(),
+            Op::StackSize => self.parse_stack_size(),
+            Op::LoadChar => self.parse_load_char(),
+            Op::JeqImm => self.parse_jeq_imm(),
+            Op::TryBegin => self.parse_try_begin(),
+            Op::TryEnd => self.parse_try_end(),
+            Op::TeeVar => self.parse_tee_var(),
+            Op::Cmp3 => self.parse_cmp3(),
+            Op::LoadConstStr => self.parse_load_const_str(),
+            Op::Pick => self.parse_pick(),
+            Op::Switch => self.parse_switch(),
+            Op::CallRel => self.parse_call_rel(),
+            Op::LoadRet => self.parse_load_ret(),
+            Op::WriteUpvar => self.parse_write_upvar(),
+            Op::ReadUpvar => self.parse_read_upvar(),
+            Op::CmpStrCi => self.parse_cmp_str_ci(),
+            Op::Nop => self.parse_nop(),
+            Op::PushStr => self.parse_push_str(),
+            Op::PopStr => self.parse_pop_str(),
+        }
     }
 
     /// Parse `program` and generate a `Bytecode`
-    pub fn parse(mut self) -> anyhow::Result<Bytecode<'a>> {
+    pub fn parse(mut self) -> anyhow::Result<Bytecode> {
+        let mut bytecode = Bytecode::new();
+        self.parse_into(&mut bytecode)?;
+        bytecode.str_pool = self.str_pool;
+
+        if !bytecode.fn_table.contains_key(ENTRY_POINT) {
+            return Err(anyhow!("Could not find the entry point(MAIN)."));
+        }
+
+        Ok(bytecode)
+    }
+
+    /// Parse `program` as a library, which is a set of functions that are meant to be combined
+    /// with other modules. Unlike `parse`, `MAIN` is not required and the call of it is not
+    /// injected, so the bytecode only contains the defined functions.
+    pub fn parse_library(mut self) -> anyhow::Result<Bytecode> {
+        let mut bytecode = Bytecode::default();
+        self.parse_into(&mut bytecode)?;
+        bytecode.str_pool = self.str_pool;
+
+        Ok(bytecode)
+    }
+
+    /// Same as `parse`, but the program is read from `reader` line by line, so the whole source
+    /// is never held in memory. Only the bytecode and the constant pool grow with the program.
+    pub fn parse_reader(mut reader: impl BufRead) -> anyhow::Result<Bytecode> {
         let mut bytecode = Bytecode::new();
-        let mut line_ctr = 0;
+        let mut str_pool = Vec::new();
+        let mut str_ids = HashMap::new();
+        let mut constants = HashMap::new();
+
+        let mut source = String::new();
+        let mut line = 1;
+        // The line is read with its '\n', so that it is tokenized the same as in a whole program
+        while reader.read_line(&mut source)? > 0 {
+            let mut parser = Parser::new(&source);
+            parser.line = line;
+            parser.str_pool = str_pool;
+            parser.str_ids = str_ids;
+            parser.constants = constants;
+
+            parser.parse_into(&mut bytecode)?;
+
+            // A lone '\r' ends a line as well, so the parser keeps track of the next line
+            line = parser.line;
+            str_pool = parser.str_pool;
+            str_ids = parser.str_ids;
+            constants = parser.constants;
+            source.clear();
+        }
+        bytecode.str_pool = str_pool;
+
+        if !bytecode.fn_table.contains_key(ENTRY_POINT) {
+            return Err(anyhow!("Could not find the entry point(MAIN)."));
+        }
+
+        Ok(bytecode)
+    }
 
-        while let Some(token) = self.lexer.next_token()? {
+    /// Parse `program` and append its instructions and functions to `bytecode`
+    fn parse_into(&mut self, bytecode: &mut Bytecode) -> anyhow::Result<()> {
+        while let Some(token) = self.next_token()? {
+            let line = self.line;
             match token {
-                Token::Instruction(op) => bytecode
-                    .instructions
-                    .push((self.parse_fns[&op])(&mut self)?),
+                Token::Instruction(mut op) => {
+                    loop {
+                        let instruction = self.parse_instruction(op)?;
+                        bytecode.push(instruction, line);
+
+                        // Instructions on the same line are separated by ';', eg. "LOAD_VAL 1; INCR"
+                        match self.next_token()? {
+                            Some(Token::Newline) | None => break,
+                            Some(Token::Semicolon) => match self.next_token()? {
+                                Some(Token::Instruction(next_op)) => op = next_op,
+                                token => {
+                                    return Err(anyhow!(
+                                        "Expected instruction after ';', got {:?}",
+                                        token
+                                    ))
+                                }
+                            },
+                            Some(token) => return Err(anyhow!("Expected '\n', got {:?}", token)),
+                        }
+                    }
+                    continue;
+                }
+                Token::Name("CONST") => {
+                    self.parse_const()?;
+                    // Constants are resolved while parsing, the line itself does nothing
+                    bytecode.push(Instruction::Nop, line);
+                }
                 Token::Name(name) => {
-                    if self.lexer.next_token()? != Some(Token::Colon) {
+                    if self.next_token()? != Some(Token::Colon) {
+                        // A misspelled instruction, eg. "MULT", would otherwise be reported as
+                        // a label without ':'
+                        if looks_like_instruction(name) {
+                            return Err(anyhow!("unknown instruction '{}'", name));
+                        }
+
                         // Eg. "MAIN:"
                         return Err(anyhow!("':' should come after a label"));
                     }
@@ -171,38 +984,65 @@ impl<'a> Parser<'a> {
                         return Err(anyhow!("Function {} is already defined.", name));
                     }
 
-                    bytecode.fn_table.insert(
-                        name,
-                        Function {
-                            name,
-                            ptr: line_ctr + 2, // +2 because we inserted two instructions at the begining
-                        },
-                    );
+                    // Attributes come after the label, eg. "SQUARE: PURE"
+                    let pure = match *self.peek_token()? {
+                        Some(Token::Name("PURE")) => {
+                            self.next_token()?;
+                            true
+                        }
+                        Some(Token::Name(attribute)) => {
+                            return Err(anyhow!("Unknown function attribute '{}'", attribute))
+                        }
+                        _ => false,
+                    };
+
+                    // The function starts at the `Nop` that is pushed for the label
+                    let ptr = bytecode.instructions.len();
+                    let name: Rc<str> = name.into();
+                    bytecode
+                        .fn_table
+                        .insert(Rc::clone(&name), Function { name, ptr, pure });
 
-                    bytecode.instructions.push(Instruction::Nop); // We are adding nop to avoid function address to be shifted up
+                    bytecode.push(Instruction::Nop, line); // We are adding nop to avoid function address to be shifted up
                 }
                 Token::Newline => {
-                    bytecode.instructions.push(Instruction::Nop);
-                    line_ctr += 1;
+                    bytecode.push(Instruction::Nop, line);
                     continue;
                 }
                 token => return Err(anyhow!("Expected instruction or label, got {:?}", token)),
             }
 
-            // This instruction is finished so we expect a newline
-            match self.lexer.next_token()? {
+            // The label is finished so we expect a newline
+            match self.next_token()? {
                 Some(Token::Newline) | None => {}
                 Some(token) => return Err(anyhow!("Expected '\n', got {:?}", token)),
             }
-
-            line_ctr += 1;
         }
 
-        if !bytecode.fn_table.contains_key("MAIN") {
-            return Err(anyhow!("Could not find the entry point(MAIN)."));
+        Ok(())
+    }
+
+    /// Parse `program` as a single instruction, eg. to evaluate it on its own. Labels are not
+    /// allowed, and an empty program is a `Nop`.
+    pub fn parse_line(mut self) -> ParseRes {
+        let instruction = match self.next_token()? {
+            Some(Token::Instruction(op)) => self.parse_instruction(op)?,
+            Some(Token::Newline) | None => Instruction::Nop,
+            Some(Token::Name(name)) if looks_like_instruction(name) => {
+                return Err(anyhow!("unknown instruction '{}'", name))
+            }
+            Some(token) => return Err(anyhow!("Expected instruction, got {:?}", token)),
+        };
+
+        match self.next_token()? {
+            Some(Token::Newline) | None => {}
+            Some(token) => return Err(anyhow!("Expected '\n', got {:?}", token)),
+        }
+        if let Some(token) = self.next_token()? {
+            return Err(anyhow!("Expected a single line, got {:?}", token));
         }
 
-        Ok(bytecode)
+        Ok(instruction)
     }
 
     // For instructions that contain data, the generated function:
@@ -213,18 +1053,127 @@ impl<'a> Parser<'a> {
     impl_parse_fn! {parse_write_var; WriteVar(StringLiteral)}
     impl_parse_fn! {parse_read_var; ReadVar(StringLiteral)}
     impl_parse_fn! {parse_load_val; LoadVal(Number)}
-    impl_parse_fn! {parse_call; Call(Name)}
     impl_parse_fn! {parse_halt; Halt(Number)}
     impl_parse_fn! {parse_jmp; Jmp(Number)}
     impl_parse_fn! {parse_je; Je(Number)}
     impl_parse_fn! {parse_jne; Jne(Number)}
     impl_parse_fn! {parse_jg; Jg(Number)}
     impl_parse_fn! {parse_jl; Jl(Number)}
-    impl_parse_fn! {parse_push_str; PushStr(StringLiteral)}
+    impl_parse_fn! {parse_jz; Jz(Number)}
+    impl_parse_fn! {parse_jnz; Jnz(Number)}
+    impl_parse_fn! {parse_je_str; JeStr(Number)}
+    impl_parse_fn! {parse_jne_str; JneStr(Number)}
+    impl_parse_fn! {parse_write_str_var; WriteStrVar(StringLiteral)}
+    impl_parse_fn! {parse_read_str_var; ReadStrVar(StringLiteral)}
+    impl_parse_fn! {parse_drop_n; DropN(Number)}
+    impl_parse_fn! {parse_read_var_or; ReadVarOr(StringLiteral, Number)}
+    impl_parse_fn! {parse_ret_values; RetValues(Number)}
+    impl_parse_fn! {parse_jge; Jge(Number)}
+    impl_parse_fn! {parse_jle; Jle(Number)}
+
+    /// Literals of `PUSH_STR` are interned, so that the instructions of the same literal share the
+    /// string of the constant pool instead of holding a copy each.
+    fn parse_push_str(&mut self) -> ParseRes {
+        let literal = match self.next_token()? {
+            Some(Token::StringLiteral(literal)) => literal,
+            token => return Err(anyhow!("Expected StringLiteral, got {:?}", token)),
+        };
+
+        let index = match self.str_ids.get(literal) {
+            Some(&index) => index,
+            None => {
+                let literal: Rc<str> = literal.into();
+                self.str_pool.push(Rc::clone(&literal));
+                self.str_ids.insert(literal, self.str_pool.len() - 1);
+                self.str_pool.len() - 1
+            }
+        };
+
+        Ok(Instruction::PushStr(Rc::clone(&self.str_pool[index])))
+    }
+
+    /// `SWITCH` takes any number of offsets until the end of the instruction. The first one is the
+    /// default.
+    fn parse_switch(&mut self) -> ParseRes {
+        let mut offsets = Vec::new();
+        loop {
+            let offset = match *self.peek_token()? {
+                Some(Token::Number(offset)) => offset,
+                Some(Token::Name(name)) => match self.constants.get(name) {
+                    Some(&value) => value,
+                    None => break,
+                },
+                _ => break,
+            };
+            self.next_token()?;
+            offsets.push(
+                i32::try_from(offset)
+                    .map_err(|_| anyhow!("{} is out of range for Switch", offset))?,
+            );
+        }
+
+        if offsets.is_empty() {
+            return Err(anyhow!("SWITCH expects at least a default offset"));
+        }
+
+        Ok(Instruction::Switch(offsets.into()))
+    }
+
+    /// `LOAD_CHAR` is a `LOAD_VAL` of the code point of a character, so it has no instruction of
+    /// its own. Supported escapes are `\n`, `\t`, `\r`, `\0` and `\\`.
+    fn parse_load_char(&mut self) -> ParseRes {
+        let literal = match self.next_token()? {
+            Some(Token::StringLiteral(literal)) => literal,
+            token => return Err(anyhow!("Expected StringLiteral, got {:?}", token)),
+        };
+
+        let mut chars = literal.chars();
+        let ch = match (chars.next(), chars.next(), chars.next()) {
+            (Some('\\'), Some(escaped), None) => match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' => '\\',
+                _ => return Err(anyhow!("Unknown escape '\\{}'", escaped)),
+            },
+            (Some(ch), None, _) => ch,
+            _ => {
+                return Err(anyhow!(
+                    "LOAD_CHAR expects a single character, got '{}'",
+                    literal
+                ))
+            }
+        };
+
+        Ok(Instruction::LoadVal(ch as i64))
+    }
+
+    /// `PRINT` is both an instruction and a built-in function, so it is lexed as an instruction
+    /// even though it is called as a function here.
+    fn parse_call(&mut self) -> ParseRes {
+        match self.next_token()? {
+            Some(Token::Name(name)) => Ok(Instruction::Call(name.into())),
+            Some(Token::Instruction(Op::Print)) => Ok(Instruction::Call("PRINT".into())),
+            token => Err(anyhow!("Expected Name, got {:?}", token)),
+        }
+    }
+    impl_parse_fn! {parse_add_imm; AddImm(Number)}
+    impl_parse_fn! {parse_sub_imm; SubImm(Number)}
+    impl_parse_fn! {parse_swap_var; SwapVar(StringLiteral, StringLiteral)}
+    impl_parse_fn! {parse_jeq_imm; JeqImm(Number, Number)}
+    impl_parse_fn! {parse_try_begin; TryBegin(Number)}
+    impl_parse_fn! {parse_tee_var; TeeVar(StringLiteral)}
+    impl_parse_fn! {parse_load_const_str; LoadConstStr(Number)}
+    impl_parse_fn! {parse_pick; Pick(Number)}
+    impl_parse_fn! {parse_call_rel; CallRel(Number)}
+    impl_parse_fn! {parse_write_upvar; WriteUpvar(StringLiteral)}
+    impl_parse_fn! {parse_read_upvar; ReadUpvar(StringLiteral)}
 
     // For instructions that do not contain data, the generated function
     // just returns the given Instruction.
     impl_parse_fn! {parse_add; Add}
+    impl_parse_fn! {parse_sub; Sub}
     impl_parse_fn! {parse_mul; Mul}
     impl_parse_fn! {parse_decr; Decr}
     impl_parse_fn! {parse_incr; Incr}
@@ -234,4 +1183,19 @@ impl<'a> Parser<'a> {
     impl_parse_fn! {parse_cmp; Cmp}
     impl_parse_fn! {parse_cmp_str; CmpStr}
     impl_parse_fn! {parse_pop_str; PopStr}
+    impl_parse_fn! {parse_sign; Sign}
+    impl_parse_fn! {parse_min; Min}
+    impl_parse_fn! {parse_max; Max}
+    impl_parse_fn! {parse_abs; Abs}
+    impl_parse_fn! {parse_pow; Pow}
+    impl_parse_fn! {parse_and_b; AndB}
+    impl_parse_fn! {parse_or_b; OrB}
+    impl_parse_fn! {parse_assert_eq; AssertEq}
+    impl_parse_fn! {parse_print; Print}
+    impl_parse_fn! {parse_bool; Bool}
+    impl_parse_fn! {parse_stack_size; StackSize}
+    impl_parse_fn! {parse_try_end; TryEnd}
+    impl_parse_fn! {parse_cmp3; Cmp3}
+    impl_parse_fn! {parse_load_ret; LoadRet}
+    impl_parse_fn! {parse_cmp_str_ci; CmpStrCi}
 }
@@ -1,27 +1,81 @@
+//! Lexer that splits a bci program into `Token`s
+//!
+//! # Example
+//!
+//! ```
+//! use bci::lexer::Lexer;
+//! use bci::token::{Op, Token};
+//!
+//! let mut lexer = Lexer::new("MAIN:\nPUSH_STR 'hi'\nLOAD_VAL -3");
+//!
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Name("MAIN")));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Colon));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Newline));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Instruction(Op::PushStr)));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::StringLiteral("hi")));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Newline));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Instruction(Op::LoadVal)));
+//! assert_eq!(lexer.next_token().unwrap(), Some(Token::Number(-3)));
+//! assert_eq!(lexer.next_token().unwrap(), None);
+//! ```
+
 use super::token::Token;
 use anyhow::anyhow;
 use std::str;
 
+/// Lexer over a borrowed program. Tokens borrow their text from the program, so no string is
+/// copied while lexing.
 pub struct Lexer<'a> {
     program: &'a str,
     cursor: usize,
+    lex_comments: bool,
 }
 
 type LResult<'a> = anyhow::Result<Option<Token<'a>>>;
 
 impl<'a> Lexer<'a> {
+    /// Create a lexer that starts from the beginning of `program`
     pub fn new(program: &'a str) -> Self {
-        Lexer { program, cursor: 0 }
+        Lexer {
+            program,
+            cursor: 0,
+            lex_comments: false,
+        }
+    }
+
+    /// Emit comments as `Token::Comment` instead of skipping them. This is useful for tools
+    /// like formatters that need to preserve the comments.
+    pub fn set_lex_comments(&mut self, lex_comments: bool) {
+        self.lex_comments = lex_comments;
     }
 
     /// Get the next token. This consumes the tokens.
+    ///
+    /// Returns `Ok(None)` once the end of the program is reached, and an error for malformed
+    /// tokens such as unfinished string literals or numbers that do not fit in a value.
     pub fn next_token(&mut self) -> LResult<'a> {
         self.trim();
 
         match self.next_char(false) {
+            Some(b'#') => {
+                let comment = self.read_comment();
+                if self.lex_comments {
+                    Ok(Some(comment))
+                } else {
+                    self.next_token()
+                }
+            }
             Some(b'\'') => self.read_str_literal(),
             Some(b':') => Ok(Some(Token::Colon)),
             Some(b'\n') => Ok(Some(Token::Newline)),
+            Some(b'\r') => {
+                // "\r\n" is a single line ending, and so is a lone '\r'
+                if self.next_char(true) == Some(b'\n') {
+                    self.cursor += 1;
+                }
+                Ok(Some(Token::Newline))
+            }
+            Some(b';') => Ok(Some(Token::Semicolon)),
             Some(ch) => {
                 if ch.is_ascii_digit() || ch == b'-' {
                     self.read_number()
@@ -34,10 +88,10 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    /// Trim whitespaces, tabs, carriage returns, control chars
+    /// Trim whitespaces, tabs, control chars. Carriage returns end a line, so they are not trimmed.
     fn trim(&mut self) {
         while let Some(ch) = self.next_char(true) {
-            if ch != b'\t' && ch != b'\r' && ch != b'\x0C' && ch != b' ' {
+            if ch != b'\t' && ch != b'\x0C' && ch != b' ' {
                 break;
             }
             self.cursor += 1;
@@ -54,8 +108,7 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let number =
-            str::from_utf8(&self.program.as_bytes()[start_pos..self.cursor])?.parse::<i32>()?;
+        let number = self.program[start_pos..self.cursor].parse::<i64>()?;
 
         Ok(Some(Token::Number(number)))
     }
@@ -66,7 +119,7 @@ impl<'a> Lexer<'a> {
         let _ = self.next_char(false);
         let start_pos = self.cursor - 1;
         while let Some(ch) = self.next_char(false) {
-            if ch == b'\n' {
+            if ch == b'\n' || ch == b'\r' {
                 // Strings cannot continue from next line
                 break;
             } else if ch == b'\'' {
@@ -78,11 +131,24 @@ impl<'a> Lexer<'a> {
         if !finished {
             Err(anyhow!("String literal is not finished properly."))
         } else {
-            let str_lit = str::from_utf8(&self.program.as_bytes()[start_pos..self.cursor - 1])?;
+            let str_lit = &self.program[start_pos..self.cursor - 1];
             Ok(Some(Token::StringLiteral(str_lit)))
         }
     }
 
+    /// Read a comment that starts with '#' and lasts until the end of the line
+    fn read_comment(&mut self) -> Token<'a> {
+        let start_pos = self.cursor;
+        while let Some(ch) = self.next_char(true) {
+            if ch == b'\n' || ch == b'\r' {
+                break;
+            }
+            self.cursor += 1;
+        }
+
+        Token::Comment(&self.program[start_pos..self.cursor])
+    }
+
     /// Read any other token
     fn read_token(&mut self) -> LResult<'a> {
         let start_pos = self.cursor - 1;
@@ -94,7 +160,7 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let token_str = str::from_utf8(&self.program.as_bytes()[start_pos..self.cursor])?;
+        let token_str = &self.program[start_pos..self.cursor];
         Ok(Some(Token::new(token_str)))
     }
 
@@ -118,10 +184,15 @@ mod tests {
 
     #[test]
     fn trim() {
-        let program = "\t\r\x0C PUSH_STR 'hello'";
+        let program = "\t\x0C PUSH_STR 'hello'";
         let mut lexer = Lexer::new(program);
         lexer.trim();
         assert_eq!(&lexer.program[lexer.cursor..], "PUSH_STR 'hello'");
+
+        // Carriage returns end a line
+        let mut lexer = Lexer::new(" \r\nPUSH_STR 'hello'");
+        lexer.trim();
+        assert_eq!(&lexer.program[lexer.cursor..], "\r\nPUSH_STR 'hello'");
     }
 
     #[test]
@@ -160,6 +231,59 @@ mod tests {
         let _ = lexer.next_char(false);
     }
 
+    #[test]
+    fn char_literal() {
+        let mut lexer = Lexer::new("LOAD_CHAR '\\n'");
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::Instruction(Op::LoadChar))
+        );
+        // Escapes are resolved by the parser
+        assert_eq!(
+            lexer.next_token().unwrap(),
+            Some(Token::StringLiteral("\\n"))
+        );
+        assert_eq!(lexer.next_token().unwrap(), None);
+    }
+
+    #[test]
+    fn comments() {
+        let program = "# entry\nMAIN: # no args\nHALT 0#done";
+
+        let mut lexer = Lexer::new(program);
+        lexer.set_lex_comments(true);
+        let tokens = vec![
+            Token::Comment(" entry"),
+            Token::Newline,
+            Token::Name("MAIN"),
+            Token::Colon,
+            Token::Comment(" no args"),
+            Token::Newline,
+            Token::Instruction(Op::Halt),
+            Token::Number(0),
+            Token::Comment("done"),
+        ];
+        for token in tokens {
+            assert_eq!(token, lexer.next_token().unwrap().unwrap());
+        }
+        assert_eq!(lexer.next_token().unwrap(), None);
+
+        // Comments are skipped by default
+        let mut lexer = Lexer::new(program);
+        let tokens = vec![
+            Token::Newline,
+            Token::Name("MAIN"),
+            Token::Colon,
+            Token::Newline,
+            Token::Instruction(Op::Halt),
+            Token::Number(0),
+        ];
+        for token in tokens {
+            assert_eq!(token, lexer.next_token().unwrap().unwrap());
+        }
+        assert_eq!(lexer.next_token().unwrap(), None);
+    }
+
     #[test]
     fn read_program() {
         let program = r"
@@ -211,12 +335,58 @@ mod tests {
             Token::Newline,
         ];
 
-        let mut tokens = tokens.into_iter();
-        while let Some(token) = tokens.next() {
+        for token in tokens {
             assert_eq!(token, lexer.next_token().unwrap().unwrap());
         }
 
         // No tokens left
         assert_eq!(lexer.next_token().unwrap(), None);
     }
+
+    #[test]
+    fn semicolon() {
+        let mut lexer = Lexer::new("LOAD_VAL 1;LOAD_VAL 2 ; ADD");
+        let tokens = vec![
+            Token::Instruction(Op::LoadVal),
+            Token::Number(1),
+            Token::Semicolon,
+            Token::Instruction(Op::LoadVal),
+            Token::Number(2),
+            Token::Semicolon,
+            Token::Instruction(Op::Add),
+        ];
+        for token in tokens {
+            assert_eq!(lexer.next_token().unwrap(), Some(token));
+        }
+        assert_eq!(lexer.next_token().unwrap(), None);
+    }
+
+    #[test]
+    fn line_endings() {
+        let program = "# entry\nMAIN: # no args\nPUSH_STR 'hi'\n\nHALT 0\n";
+        fn tokens(program: &str) -> Vec<Token<'_>> {
+            let mut lexer = Lexer::new(program);
+            lexer.set_lex_comments(true);
+            let mut tokens = Vec::new();
+            while let Some(token) = lexer.next_token().unwrap() {
+                tokens.push(token);
+            }
+            tokens
+        }
+
+        let lf = tokens(program);
+        assert_eq!(
+            lf.iter().filter(|&token| *token == Token::Newline).count(),
+            5
+        );
+        assert_eq!(tokens(&program.replace('\n', "\r\n")), lf);
+        assert_eq!(tokens(&program.replace('\n', "\r")), lf);
+
+        // Mixed line endings
+        let mixed = "# entry\r\nMAIN: # no args\rPUSH_STR 'hi'\n\r\nHALT 0\r";
+        assert_eq!(tokens(mixed), lf);
+
+        // A string literal cannot continue after a carriage return either
+        assert!(Lexer::new("'hello\r'").next_token().is_err());
+    }
 }
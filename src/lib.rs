@@ -46,12 +46,67 @@
 //! | Jg          | JG _number_            | Jump if the previous `CMP` resulted in `greater`. |
 //! | Jl          | JL _number_            | Jump if the previous `CMP` resulted in `less`. |
 //! | Add         | ADD                    | Pop two values from stack and add them. Push the result on stack. |
+//! | Sub         | SUB                    | Pop two values from stack and subtract them. Push `lhs - rhs` on stack where `lhs` is the first value that is pushed on stack. |
 //! | Mul         | MUL                    | Pop two values from stack and multiply them. Push the result on stack. |
 //! | Decr        | DECR                   | Pop a value from stack and decrement it. Push the result on stack. |
 //! | Incr        | INCR                   | Pop a value from stack and increment it. Push the result on stack. |
-//! | RetValue    | RETURN_VALUE           | Return a value from a function. Pop a value from stack and save it to stack frame. Jump to the return address. |
+//! | RetValue    | RETURN_VALUE           | Return a value from a function. Pop a value from stack and save it to stack frame. Jump to the return address and push the value on stack for the caller. It can be loaded again with `LOAD_RET`. |
+//! | Jz          | JZ _number_            | Pop a value from stack and jump if it is `0`. |
+//! | Jnz         | JNZ _number_           | Pop a value from stack and jump if it is not `0`. |
+//! | Sign        | SIGN                   | Pop a value from stack and push `-1`, `0` or `1` if it is negative, zero or positive respectively. |
+//! | Min         | MIN                    | Pop two values from stack and push the smaller one. |
+//! | Max         | MAX                    | Pop two values from stack and push the larger one. |
+//! | Abs         | ABS                    | Pop a value from stack and push its absolute value. Fails for the minimum value since it has no positive counterpart. |
+//! | Pow         | POW                    | Pop the exponent and the base from stack and push `base ^ exponent`. Fails on overflow or a negative exponent. |
+//! | JeStr       | JE_STR _number_        | Pop two strings from stack and jump if they are equal. |
+//! | JneStr      | JNE_STR _number_       | Pop two strings from stack and jump if they are not equal. |
+//! | WriteStrVar | WRITE_STR_VAR '_var_name_' | Pop a string from stack and create/modify a string variable named `var_name` |
+//! | ReadStrVar  | READ_STR_VAR '_var_name_' | Read the string variable named `var_name` and push it on stack |
+//! | DropN       | DROP_N _number_        | Pop `number` values from stack at once. Fails if the stack has less values. |
+//! | ReadVarOr   | READ_VAR_OR '_var_name_' _number_ | Read the variable named `var_name` and push it on stack. Push `number` instead if the variable does not exist. |
+//! | AndB        | AND_B                  | Pop two values from stack and push `1` if both are nonzero, `0` otherwise. |
+//! | OrB         | OR_B                   | Pop two values from stack and push `1` if any of them is nonzero, `0` otherwise. |
+//! | Ret         | RETURN                 | Return from a function without a value. Nothing is pushed on stack, so the caller can check it with `LOAD_RET`. |
+//! | RetValues   | RETURN_VALUES _number_ | Return `number` values from a function. The values stay on stack in the order they are pushed. Jump to the return address. |
+//! | AssertEq    | ASSERT_EQ              | Pop two values from stack and continue if they are equal. Otherwise, print a message and halt with exit code `1`. |
+//! | Jge         | JGE _number_           | Jump if the previous `CMP` resulted in `greater` or `equals`. |
+//! | Jle         | JLE _number_           | Jump if the previous `CMP` resulted in `less` or `equals`. |
+//! | Print       | PRINT                  | Pop a value from stack and print it. Same as `CALL PRINT`. |
+//! | AddImm      | ADD_IMM _number_       | Pop a value from stack and add `number` to it. Push the result on stack. |
+//! | SubImm      | SUB_IMM _number_       | Pop a value from stack and subtract `number` from it. Push the result on stack. |
+//! | Bool        | BOOL                   | Pop a value from stack and push `1` if it is nonzero, `0` otherwise. |
+//! | SwapVar     | SWAP_VAR '_a_' '_b_'   | Swap the values of the variables named `a` and `b`. Fails if any of them does not exist. |
+//! | StackSize   | STACK_SIZE             | Push the number of memory cells that are in use on stack. A string occupies multiple cells. |
+//! | LoadChar    | LOAD_CHAR '_char_'     | Push the code point of `char` on stack, eg. `65` for `'A'`. Escapes `\n`, `\t`, `\r`, `\0` and `\\` are supported. |
+//! | JeqImm      | JEQ_IMM _imm_ _number_ | Pop a value from stack and jump if it equals `imm`. Same as `LOAD_VAL imm`, `CMP` and `JE number` in one step. |
+//! | TryBegin    | TRY_BEGIN _number_     | Start a try region. A runtime error until the matching `TRY_END` jumps to `current instruction + number` (like `JMP`) and pushes the error code `1` on stack instead of ending the program. |
+//! | TryEnd      | TRY_END                | End the last try region. |
+//! | TeeVar      | TEE_VAR '_var_name_'   | Create/modify a variable named `var_name` with the value on top of stack, without popping it. Same as `WRITE_VAR` followed by `READ_VAR` of the same variable. |
+//! | Cmp3        | CMP3                   | Same as `CMP` for two numbers and `CMP_STR` for two strings. The kinds of the values are tracked on stack, so the right comparison is chosen. Fails if a number is compared with a string. |
+//! | LoadConstStr | LOAD_CONST_STR _index_ | Push the string at `index` of the constant pool on stack. The pool holds the literals of `PUSH_STR` in the order they first appear, so `PUSH_STR` of the same literal shares an entry. Like `PUSH_STR`, the string is copied onto the stack. |
+//! | Pick        | PICK _index_           | Push a copy of the value at `index`. Non-negative indices count from the top, so `PICK 0` duplicates the top and `PICK 1` copies the value below it. Negative indices count from the bottom, `PICK -1` copies the deepest value. Indices are in memory cells, so a string occupies multiple indices. Fails if the index is outside of the stack. |
+//! | Switch      | SWITCH _default_ _case-0_ _case-1_ ... | Pop a selector value from stack and jump like `JMP case-n` where `n` is the selector. Jumps like `JMP default` if there is no case for the selector, eg. if it is negative. |
+//! | CallRel     | CALL_REL _number_      | Call the function that starts at `current instruction + number`, counting like `JMP`. The function returns to the next instruction as with `CALL`. Offsets are kept valid by `Bytecode::optimize`. |
+//! | LoadRet     | LOAD_RET               | Push the value that the last returned function returned with `RETURN_VALUE`, then push `1`. Push only `0` if the function returned with `RETURN` or `RETURN_VALUES`, which do not have a single return value. |
+//! | WriteUpvar  | WRITE_UPVAR '_var_name_' | Same as `WRITE_VAR`, but the variable is created/modified in the frame of the caller of the current function. Fails if there is no caller, eg. in `MAIN`. |
+//! | ReadUpvar   | READ_UPVAR '_var_name_' | Same as `READ_VAR`, but the variable is read from the frame of the caller of the current function. Fails if there is no caller, eg. in `MAIN`. |
+//! | CmpStrCi    | CMP_STR_CI             | Same as `CMP_STR`, but ASCII letters are compared ignoring their case, eg. `'TXT'` equals `'txt'`. |
 //! | Nop         | NOP                    | Do nothing. Newlines are converted to nops. |
 //!
+//! # Functions
+//!
+//! A function starts with a label, eg. `SQUARE:`, and runs until it returns. The label can be
+//! followed by `PURE`, eg. `SQUARE: PURE`, to mark a function that has no IO and always returns
+//! the same result for the same arguments. The VM does not verify it, it is recorded in
+//! `Function::pure` for the tools and optimizations that make use of it.
+//!
+//! # Constants
+//!
+//! `CONST _NAME_ _number_` defines a named constant that can be used anywhere a number is
+//! expected, eg. `LOAD_VAL LIMIT`. Constants are resolved while parsing, so they must be defined
+//! before they are used. The name cannot be an instruction keyword, and a constant cannot be
+//! redefined.
+//!
 //! # Built-in functions
 //!
 //! ## TRAVERSE_DIR
@@ -61,8 +116,24 @@
 //! ### Return
 //! Object id of the iterator.
 //!
+//! ## TRAVERSE_DIR_RECURSIVE
+//! Same as `TRAVERSE_DIR`, but it walks into the subdirectories depth-first. A directory is
+//! given before its contents. Symbolic links to directories are not followed.
+//! ### Parameters
+//! - _dir_name_: Name of the directory
+//! ### Return
+//! Object id of the iterator.
+//!
+//! ## TRAVERSE_DIR_SORTED
+//! Same as `TRAVERSE_DIR`, but the items are sorted by their paths so that the order is the same
+//! on every platform.
+//! ### Parameters
+//! - _dir_name_: Name of the directory
+//! ### Return
+//! Object id of the iterator.
+//!
 //! ## TRAVERSE_DIR_NEXT
-//! Gives the next file or directory. Should be called after `TRAVERSE` and until the returned value is `0`.
+//! Gives the next file or directory. Should be called after `TRAVERSE_DIR` (or one of its variants) and until the returned value is `0`.
 //! ### Parameters
 //! - _iterator_: Object id that is returned from `TRAVERSE_DIR`
 //! ### Return
@@ -71,44 +142,233 @@
 //! - Extension of the file if any, or `0`.
 //! - Whether the item is a directory or not (`1` or `0`).
 //! - Item exists (`1`)
-
+//!
 //! Else `0` is pushed on stack respectively.
 //!
 //! ## READ_FILE
-//! Starts a read file process. File will be read line-by-line.
+//! Starts a read file process. File will be read line-by-line. Fails with `vm::BciError::Io` if
+//! the file cannot be opened, which can be caught with `TRY_BEGIN`.
 //! ### Parameters
 //! - _file_path_: Path to file. (absolute or relative)
 //! ### Return
 //! Object id of the iterator.
 //!
+//! ## FILE_SIZE
+//! Gets the size of a file.
+//! ### Parameters
+//! - _file_path_: Path to file. (absolute or relative)
+//! ### Return
+//! - Size of the file in bytes, or `0`.
+//! - `1` if the metadata of the file could be read, else `0`.
+//!
+//! ## FILE_MODIFIED
+//! Gets the last modification time of a file.
+//! ### Parameters
+//! - _file_path_: Path to file. (absolute or relative)
+//! ### Return
+//! - Seconds since the unix epoch, or `0`.
+//! - `1` if the modification time could be read, else `0`.
+//!
+//! ## GET_CWD
+//! Gets the directory that relative paths are resolved against. This is the current working
+//! directory of the process, or the root jail if there is one (See `BciVm::set_root_jail`).
+//! ### Return
+//! - Path of the directory if it can be determined.
+//! - `1` if there is a path, else `0`.
+//!
+//! ## READ_FILE_ALL
+//! Reads a whole file at once.
+//! ### Parameters
+//! - _file_path_: Path to file. (absolute or relative)
+//! ### Return
+//! - Content of the file if it can be read.
+//! - `1` if the file is read, else `0`. Reading fails if the file does not exist, is not valid
+//!   UTF-8 or is too big for the stack.
+//!
+//! ## READ_STDIN
+//! Starts reading the standard input line-by-line. Lines are read with `READ_FILE_NEXT`.
+//! ### Return
+//! Object id of the iterator.
+//!
 //! ## READ_FILE_NEXT
-//! Reads the next line. Should be called after `READ_FILE` and until the returned value is `0`.
+//! Reads the next line. Should be called after `READ_FILE` or `READ_STDIN` and until the returned value is `0`.
 //! ### Parameters
-//! - _iter_: Object id that is returned from `READ_FILE`.
+//! - _iter_: Object id that is returned from `READ_FILE` or `READ_STDIN`.
 //! ### Return
 //! - Line if any.
 //! - `1` if there is a line, else `0`.
 //!
+//! ## WRITE_FILE
+//! Writes a string to a file. The file is created if it does not exist, truncated otherwise.
+//! ### Parameters
+//! - _file_path_: Path to file. (absolute or relative)
+//! - _content_: String to be written.
+//! ### Return
+//! `1` if the file is written successfully, else `0`.
+//!
+//! ## APPEND_FILE
+//! Same as `WRITE_FILE`, but appends the content to the end of the file.
+//! ### Parameters
+//! - _file_path_: Path to file. (absolute or relative)
+//! - _content_: String to be appended.
+//! ### Return
+//! `1` if the file is written successfully, else `0`.
+//!
+//! ## GET_ARG
+//! Gets an argument that is passed to the program. (See `BciVm::set_args`)
+//! ### Parameters
+//! - _index_: Index of the argument, starting from `0`.
+//! ### Return
+//! - Argument if any.
+//! - `1` if there is an argument at `index`, else `0`.
+//!
+//! ## STR_SLICE
+//! Gets the part of a string between two indices.
+//! ### Parameters
+//! - _string_: String to be sliced.
+//! - _start_: Index of the first byte of the slice.
+//! - _end_: Index after the last byte of the slice. Fails if `end` is smaller than `start` or
+//!   larger than the length of the string.
+//! ### Return
+//! - Slice of the string.
+//!
+//! ## RANDOM
+//! Generates a pseudo-random number. The generator is seeded with `0` until `SEED_RANDOM` is
+//! called, so a program produces the same numbers on every run. It is not suitable for
+//! cryptography.
+//! ### Parameters
+//! - _bound_: Positive upper bound of the number.
+//! ### Return
+//! - A number in `[0, bound)`.
+//!
+//! ## SEED_RANDOM
+//! Restarts the generator of `RANDOM` from a seed. The same seed gives the same sequence.
+//! ### Parameters
+//! - _seed_: Any number.
+//!
+//! ## HASH_STR
+//! Hashes a string with 32-bit FNV-1a. The hash is stable across platforms and versions, so it
+//! can be used for bucketing.
+//! ### Parameters
+//! - _string_: String to be hashed.
+//! ### Return
+//! - Hash of the string as a non-negative number.
+//!
+//! ## FORMAT_INT
+//! Formats a number as a decimal string that is padded from the left, eg. for aligned tables.
+//! Numbers that are wider than `width` are not truncated.
+//! ### Parameters
+//! - _number_: Number to be formatted.
+//! - _width_: Minimum length of the string.
+//! - _zero-pad_: `0` to pad with spaces, any other value to pad with zeros. Zeros come after the
+//!   sign, eg. `-05`.
+//! ### Return
+//! - Padded string.
+//!
+//! ## HAS_EXTENSION
+//! Checks whether a path has an extension, ignoring ASCII case. Multi-part extensions such as
+//! `tar.gz` are supported, since the path is only checked to end with `.` and the extension.
+//! ### Parameters
+//! - _path_: Path to be checked.
+//! - _extension_: Extension with or without the leading `.`, eg. `txt` or `.txt`.
+//! ### Return
+//! - `1` if the path has the extension, `0` otherwise.
+//!
+//! ## STR_FIND
+//! Finds the first occurrence of a string in another. Both strings are consumed.
+//! ### Parameters
+//! - _haystack_: String to search in.
+//! - _needle_: String to search for.
+//! ### Return
+//! - Byte index of the first occurrence, or `-1` if the needle is not found. An empty needle is
+//!   found at `0`.
+//!
+//! ## STR_SPLIT
+//! Splits a string by a delimiter. The pieces are read with `STR_SPLIT_NEXT`.
+//! ### Parameters
+//! - _string_: String to be split.
+//! - _delimiter_: Non-empty string that separates the pieces.
+//! ### Return
+//! Object id of the iterator.
+//!
+//! ## STR_SPLIT_NEXT
+//! Gives the next piece. Should be called after `STR_SPLIT` and until the returned value is `0`.
+//! ### Parameters
+//! - _iter_: Object id that is returned from `STR_SPLIT`.
+//! ### Return
+//! - Piece if any.
+//! - `1` if there is a piece, else `0`.
+//!
 //! ## PRINT
 //! Prints the `number`.
 //! ### Parameters
 //! - _number_: The number on top of stack.
 //!
+//! ## PRINT_RAW
+//! Prints the `number` followed by a newline, without the `>>>>>` prefix of `PRINT` (See
+//! `BciVm::set_print_prefix`).
+//! ### Parameters
+//! - _number_: The number on top of stack.
+//!
+//! ## PRINT_HEX
+//! Prints the `number` in hexadecimal, eg. `0x1f`. Negative numbers are printed in two's
+//! complement.
+//! ### Parameters
+//! - _number_: The number on top of stack.
+//!
 //! ## PRINT_STR
 //! Prints the `string`.
 //! ### Parameters
 //! - _string_: String to be printed.
 //!
+//! ## SLEEP_MS
+//! Pauses the program. Embedders can change how it waits with `BciVm::set_sleep`.
+//! ### Parameters
+//! - _milliseconds_: Non-negative duration to sleep.
+//!
+//! ## PRINT_STR_PEEK
+//! Same as `PRINT_STR`, but the `string` stays on stack.
+//! ### Parameters
+//! - _string_: String to be printed.
+//!
+//! ## DUMP_STACK
+//! Prints the whole stack from bottom to top for debugging. The stack is left as is. Note that
+//! strings are printed as their raw memory cells.
+//!
+//! ## DUMP_VARS
+//! Prints the variables of the current function as `name = value`, sorted by their names. String
+//! variables are not printed.
+//!
+//! Built-in functions are grouped by capabilities (see `vm::Capability`): `fs` for the
+//! filesystem, `io` for the standard input and output, `math` for `RANDOM` and `SEED_RANDOM`, and
+//! `str` for string processing such as `STR_SLICE` and `FORMAT_INT`. Embedders can enable only
+//! some of them by loading the program with `BciVm::load_with_capabilities`.
+//! Paths of the filesystem built-in functions can also be confined to a directory with
+//! `BciVm::set_root_jail`.
+//!
 //! # Important notes
 //!
 //! - Entry point is the `MAIN` function. Every program should implement it.
 //! - Every piece of code should be written under a function. There is no global code/variable mechanism.
 //! - Improper use of stack and call/return flow will result in undefined behaviour.
-//! - Each insruction is seperated with newline
+//! - Each insruction is seperated with newline, or with `;` on the same line, eg.
+//!   `LOAD_VAL 1; INCR`. Jump offsets count instructions, so every instruction after a `;` also
+//!   counts as a line.
+//! - Running past the last instruction of the program halts it with exit code `0`.
+//! - Once `MAIN` returns, the program halts with the value that it returned with `RETURN_VALUE`
+//!   as the exit code, or `0` if it returned with `RETURN`.
+//! - `CMP` pushes its result on stack by default, so anything that is pushed before the
+//!   conditional jump changes the branch. `BciVm::set_cmp_mode(CmpMode::Flags)` stores the result
+//!   in a flags register instead.
+//! - Comments start with `#` and last until the end of the line
+//! - Arithmetic instructions fail on overflow by default. See `vm::ArithmeticMode` for wrapping
+//!   and saturating arithmetic.
+//! - The stack has 1000 cells. Pushing to a full stack fails with an error that a try region can
+//!   catch.
 //!
 //!
 
 pub mod bytecode;
-mod lexer;
+pub mod lexer;
 pub mod token;
 pub mod vm;
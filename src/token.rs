@@ -1,3 +1,5 @@
+//! Tokens of a bci program
+
 /// Reserved keywords of our bytecode
 /// ***Note that built-in functions are not reserved keywords***
 #[derive(Debug, Hash, Eq, PartialEq)]
@@ -11,6 +13,7 @@ pub enum Op {
     Return,
     Mul,
     Add,
+    Sub,
     Decr,
     Incr,
     Jmp,
@@ -23,24 +26,76 @@ pub enum Op {
     Jg,
     Jl,
     CmpStr,
+    Jz,
+    Jnz,
+    Sign,
+    Min,
+    Max,
+    Abs,
+    Pow,
+    JeStr,
+    JneStr,
+    WriteStrVar,
+    ReadStrVar,
+    DropN,
+    ReadVarOr,
+    AndB,
+    OrB,
+    ReturnValues,
+    AssertEq,
+    Jge,
+    Jle,
+    Print,
+    AddImm,
+    SubImm,
+    Bool,
+    SwapVar,
+    StackSize,
+    LoadChar,
+    JeqImm,
+    TryBegin,
+    TryEnd,
+    TeeVar,
+    Cmp3,
+    LoadConstStr,
+    Pick,
+    Switch,
+    CallRel,
+    LoadRet,
+    WriteUpvar,
+    ReadUpvar,
+    CmpStrCi,
 }
 
+/// Tokens produced by the `Lexer`
 #[derive(Debug, Eq, PartialEq)]
 pub enum Token<'a> {
+    /// A reserved instruction keyword, eg. `LOAD_VAL`
     Instruction(Op),
 
+    /// End of a line
     Newline,
 
+    /// `;` that separates instructions on the same line
+    Semicolon,
+
     SingleQuotes,
+    /// `:` that ends a label
     Colon,
 
+    /// Contents of a string literal without the surrounding `'`
     StringLiteral(&'a str),
+    /// Any other word such as a label or a built-in function name
     Name(&'a str),
+    /// Text of a comment without the leading `#`. Only emitted if the lexer is told to.
+    Comment(&'a str),
 
-    Number(i32),
+    /// A decimal number
+    Number(i64),
 }
 
 impl<'a> Token<'a> {
+    /// Create a keyword or a name token from a word
     pub fn new(token_str: &'a str) -> Self {
         match token_str {
             "LOAD_VAL" => Token::Instruction(Op::LoadVal),
@@ -49,6 +104,7 @@ impl<'a> Token<'a> {
             "RETURN_VALUE" => Token::Instruction(Op::ReturnValue),
             "MUL" => Token::Instruction(Op::Mul),
             "ADD" => Token::Instruction(Op::Add),
+            "SUB" => Token::Instruction(Op::Sub),
             "JMP" => Token::Instruction(Op::Jmp),
             "CALL" => Token::Instruction(Op::Call),
             "HALT" => Token::Instruction(Op::Halt),
@@ -63,6 +119,45 @@ impl<'a> Token<'a> {
             "RETURN" => Token::Instruction(Op::Return),
             "PUSH_STR" => Token::Instruction(Op::PushStr),
             "POP_STR" => Token::Instruction(Op::PopStr),
+            "JZ" => Token::Instruction(Op::Jz),
+            "JNZ" => Token::Instruction(Op::Jnz),
+            "SIGN" => Token::Instruction(Op::Sign),
+            "MIN" => Token::Instruction(Op::Min),
+            "MAX" => Token::Instruction(Op::Max),
+            "ABS" => Token::Instruction(Op::Abs),
+            "POW" => Token::Instruction(Op::Pow),
+            "JE_STR" => Token::Instruction(Op::JeStr),
+            "JNE_STR" => Token::Instruction(Op::JneStr),
+            "WRITE_STR_VAR" => Token::Instruction(Op::WriteStrVar),
+            "READ_STR_VAR" => Token::Instruction(Op::ReadStrVar),
+            "DROP_N" => Token::Instruction(Op::DropN),
+            "READ_VAR_OR" => Token::Instruction(Op::ReadVarOr),
+            "AND_B" => Token::Instruction(Op::AndB),
+            "OR_B" => Token::Instruction(Op::OrB),
+            "RETURN_VALUES" => Token::Instruction(Op::ReturnValues),
+            "ASSERT_EQ" => Token::Instruction(Op::AssertEq),
+            "JGE" => Token::Instruction(Op::Jge),
+            "JLE" => Token::Instruction(Op::Jle),
+            "PRINT" => Token::Instruction(Op::Print),
+            "ADD_IMM" => Token::Instruction(Op::AddImm),
+            "SUB_IMM" => Token::Instruction(Op::SubImm),
+            "BOOL" => Token::Instruction(Op::Bool),
+            "SWAP_VAR" => Token::Instruction(Op::SwapVar),
+            "STACK_SIZE" => Token::Instruction(Op::StackSize),
+            "LOAD_CHAR" => Token::Instruction(Op::LoadChar),
+            "JEQ_IMM" => Token::Instruction(Op::JeqImm),
+            "TRY_BEGIN" => Token::Instruction(Op::TryBegin),
+            "TRY_END" => Token::Instruction(Op::TryEnd),
+            "TEE_VAR" => Token::Instruction(Op::TeeVar),
+            "CMP3" => Token::Instruction(Op::Cmp3),
+            "LOAD_CONST_STR" => Token::Instruction(Op::LoadConstStr),
+            "PICK" => Token::Instruction(Op::Pick),
+            "SWITCH" => Token::Instruction(Op::Switch),
+            "CALL_REL" => Token::Instruction(Op::CallRel),
+            "LOAD_RET" => Token::Instruction(Op::LoadRet),
+            "WRITE_UPVAR" => Token::Instruction(Op::WriteUpvar),
+            "READ_UPVAR" => Token::Instruction(Op::ReadUpvar),
+            "CMP_STR_CI" => Token::Instruction(Op::CmpStrCi),
             "NOP" => Token::Instruction(Op::Nop),
             _ => Token::Name(token_str),
         }
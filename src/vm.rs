@@ -1,26 +1,172 @@
 //! Virtual machine that runs the bytecode
 
-use crate::bytecode::{Bytecode, Instruction, Parser};
-use anyhow::anyhow;
+use crate::bytecode::{Bytecode, Instruction, Parser, ENTRY_POINT, OPCODE_COUNT};
+use anyhow::{anyhow, Context};
 use std::{
     any::Any,
+    cmp::Ordering,
     collections::HashMap,
-    fs::{self, File},
-    io::{BufRead, BufReader, Lines},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Lines, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
+/// 32-bit FNV-1a hash of `bytes`
+fn fnv1a_32(bytes: &[u8]) -> u32 {
+    const OFFSET_BASIS: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(PRIME)
+    })
+}
+
+/// Advances the SplitMix64 generator at `state` and returns the next pseudo-random number
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
 /// Frame of memory created for every function at function call
 /// and destroyed after the function returns.
 #[derive(Debug)]
 struct StackFrame {
     ret_addr: usize,                               // instruction to run next
-    ret_value: Option<i32>,                        // optional return value
-    local_vars: HashMap<String, i32>,              // local variables
+    ret_value: Option<i64>,                        // optional return value
+    local_vars: HashMap<String, i64>,              // local variables
+    local_strs: HashMap<String, String>,           // local string variables
     dynamic_objects: HashMap<usize, Box<dyn Any>>, // dynamic objects like iterators
     dyn_obj_index: usize,                          // counter for the next id
 }
 
-type BuiltinFn<'a> = fn(&mut BciVm<'a>) -> anyhow::Result<()>;
+type BuiltinFn = fn(&mut BciVm) -> anyhow::Result<()>;
+
+/// Function that `SLEEP_MS` uses to wait, see `BciVm::set_sleep`
+pub type SleepFn = Box<dyn FnMut(Duration)>;
+
+/// Function of the embedder that can be called from the program like a built-in function.
+/// Unlike built-in functions, it can capture state.
+pub type HostFn = Box<dyn FnMut(&mut BciVm) -> anyhow::Result<()>>;
+
+/// Group of built-in functions that can be enabled or disabled together. This allows embedders
+/// to run untrusted programs without giving them access to the filesystem for example.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Capability {
+    /// Filesystem access: `TRAVERSE_DIR`, `READ_FILE`, `WRITE_FILE`, etc.
+    Fs,
+    /// Standard input and output: `READ_STDIN`, `PRINT`, `PRINT_STR`, etc.
+    Io,
+    /// Numeric computations that only affect the state of the VM: `RANDOM` and `SEED_RANDOM`
+    Math,
+    /// String processing without side effects: `STR_SLICE`, `STR_SPLIT`, `STR_FIND`, `HASH_STR`,
+    /// `FORMAT_INT`, etc.
+    Str,
+}
+
+impl Capability {
+    /// Every capability, which is what `BciVm::load` enables.
+    pub const ALL: [Capability; 4] = [
+        Capability::Fs,
+        Capability::Io,
+        Capability::Math,
+        Capability::Str,
+    ];
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::Fs => "fs",
+            Capability::Io => "io",
+            Capability::Math => "math",
+            Capability::Str => "str",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Kind of a value on the stack, eg. that a built-in function expects
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Param {
+    Number,
+    Str,
+}
+
+impl Param {
+    fn describe(&self) -> &'static str {
+        match self {
+            Param::Number => "a number",
+            Param::Str => "a string",
+        }
+    }
+}
+
+/// A built-in function along with its parameters from bottom to top of the stack
+struct Builtin {
+    capability: Capability,
+    func: BuiltinFn,
+    params: &'static [Param],
+}
+
+/// Size of a memory cell of the stack in bytes
+const CELL_SIZE: usize = std::mem::size_of::<i64>();
+
+/// Number of memory cells that the data of a string with length `str_len` occupies on stack.
+/// Length of the string is stored in an additional cell on top of the data.
+fn str_data_cells(str_len: i64) -> isize {
+    str_len as isize / CELL_SIZE as isize + 1
+}
+
+/// Line-by-line iterator that is shared by `READ_FILE` and `READ_STDIN`.
+type LineIter = Lines<Box<dyn BufRead>>;
+
+/// Iterator over the pieces of a string that is split by `STR_SPLIT`.
+type SplitIter = std::vec::IntoIter<String>;
+
+/// Directory iterator that is shared by the `TRAVERSE_DIR` variants.
+type DirIter = Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>;
+
+/// Depth-first directory iterator. Directories are yielded before their contents.
+struct RecursiveDirIter {
+    work_stack: Vec<fs::ReadDir>, // directories that are being traversed, innermost on top
+}
+
+impl Iterator for RecursiveDirIter {
+    type Item = io::Result<fs::DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.work_stack.last_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.work_stack.pop();
+                    continue;
+                }
+            };
+
+            // `file_type` does not follow symlinks, so symlinked directories are not walked into
+            let is_dir = match entry.file_type() {
+                Ok(file_type) => file_type.is_dir(),
+                Err(err) => return Some(Err(err)),
+            };
+            if is_dir {
+                match fs::read_dir(entry.path()) {
+                    Ok(dir) => self.work_stack.push(dir),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+
+            return Some(Ok(entry));
+        }
+    }
+}
 
 impl StackFrame {
     fn new(ret_addr: usize) -> Self {
@@ -28,618 +174,4879 @@ impl StackFrame {
             ret_addr,
             ret_value: None,
             local_vars: HashMap::new(),
+            local_strs: HashMap::new(),
             dynamic_objects: HashMap::new(),
             dyn_obj_index: 0,
         }
     }
 }
 
-/// Virtual machine representation
-pub struct BciVm<'a> {
-    bytecode: Bytecode<'a>,
-    ip: usize,             // instruction pointer
-    sp: isize,             // stack pointer
-    fp: isize,             // frame pointer
-    pub halt: Option<i32>, // halt flag with exit code
+/// Behaviour of the arithmetic instructions (`ADD`, `SUB`, `MUL`, `INCR`, `DECR`) on overflow
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ArithmeticMode {
+    /// Wrap around at the boundary of the type
+    Wrapping,
+    /// Clamp the result to the boundary of the type
+    Saturating,
+    /// Fail with an error
+    #[default]
+    Checked,
+}
 
-    stack: [i32; 1000],                                // the general purpose stack
-    frame_stack: Vec<StackFrame>,                      // stack for `StackFrame`'s
-    builtin_fns: HashMap<&'static str, BuiltinFn<'a>>, // built-in function map
+/// Counters of the executed instructions, useful to find the hot spots of a program
+#[derive(Debug, Default, Clone)]
+pub struct ExecutionMetrics {
+    /// Number of instructions that are executed in total
+    pub total_instructions: u64,
+    /// Number of executions per instruction keyword, eg. `JMP`
+    pub per_instruction: HashMap<&'static str, u64>,
 }
 
-impl<'a> BciVm<'a> {
-    pub fn load(program: &'a str) -> anyhow::Result<Self> {
-        let bytecode = Parser::new(program).parse()?;
+impl ExecutionMetrics {
+    /// Returns how many times the instruction with the keyword `mnemonic` is executed
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.per_instruction.get(mnemonic).copied().unwrap_or(0)
+    }
+}
+
+/// What the VM reports once the program halts, see `BciVm::set_profile`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Profile {
+    /// Nothing is reported
+    #[default]
+    Off,
+    /// The instruction counts of `ExecutionMetrics` are reported
+    Counts,
+    /// Same as `Counts`, along with the time that the program has run
+    Timed,
+}
 
-        let mut builtin_fns: HashMap<&'static str, BuiltinFn> = HashMap::new();
-        builtin_fns.insert("TRAVERSE_DIR", Self::built_in_traverse_dir);
-        builtin_fns.insert("TRAVERSE_DIR_NEXT", Self::built_in_traverse_dir_next);
-        builtin_fns.insert("READ_FILE", Self::built_in_read_file);
-        builtin_fns.insert("READ_FILE_NEXT", Self::built_in_read_file_next);
-        builtin_fns.insert("PRINT", Self::built_in_print);
-        builtin_fns.insert("PRINT_STR", Self::built_in_print_str);
+/// Exit code of a program that is halted by a failing `ASSERT_EQ`
+const ASSERTION_FAILED_EXIT_CODE: i32 = 1;
 
-        Ok(BciVm {
-            bytecode,
-            ip: 0,
-            sp: -1,
-            fp: -1,
-            halt: None,
-            stack: [0; 1000],
-            frame_stack: Vec::new(),
-            builtin_fns,
-        })
+/// Number of instructions between the clock checks of `BciVm::run_with_timeout`
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// Error of a program that runs longer than the time limit of `BciVm::run_with_timeout`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TimeoutError {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program did not finish in {:?}", self.timeout)
     }
+}
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        if self.halt.is_some() {
-            return Err(anyhow!("Program is already ended."));
+impl std::error::Error for TimeoutError {}
+
+/// Errors of the built-in functions that a program or an embedder may want to handle. A program
+/// can recover from them with `TRY_BEGIN`.
+#[derive(Debug)]
+pub enum BciError {
+    /// A file could not be opened
+    Io { path: String, source: io::Error },
+}
+
+impl fmt::Display for BciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BciError::Io { path, source } => write!(f, "cannot open '{}': {}", path, source),
         }
+    }
+}
 
-        while self.halt.is_none() {
-            let _ = self.next_instruction()?;
+impl std::error::Error for BciError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BciError::Io { source, .. } => Some(source),
         }
-        Ok(())
     }
+}
 
-    pub fn next_instruction(&mut self) -> anyhow::Result<()> {
-        let instruction = self.bytecode.instructions[self.ip].clone();
+/// Information about a frame on the call stack, see `BciVm::call_stack`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CallFrameInfo {
+    /// Instruction that runs after the function of the frame returns
+    pub ret_addr: usize,
+    /// Names of the local variables and string variables, sorted
+    pub local_vars: Vec<String>,
+}
 
-        let prev_ip = self.ip;
-        match instruction {
-            Instruction::Call(fn_name) => self.ins_call(fn_name)?,
-            Instruction::RetValue => self.ins_ret_value()?,
-            Instruction::Ret => self.ins_ret()?,
-            Instruction::Mul => self.ins_mul()?,
-            Instruction::Add => self.ins_add()?,
-            Instruction::Incr => self.ins_incr()?,
-            Instruction::Decr => self.ins_decr()?,
-            Instruction::LoadVal(number) => self.ins_load_val(number)?,
-            Instruction::ReadVar(var_name) => self.ins_read_var(var_name)?,
-            Instruction::WriteVar(var_name) => self.ins_write_var(var_name)?,
-            Instruction::PushStr(s) => self.ins_push_str(s)?,
-            Instruction::PopStr => {
-                let _ = self.ins_pop_str()?;
-            }
-            Instruction::Je(number) => self.ins_je(number)?,
-            Instruction::Jne(number) => self.ins_jne(number)?,
-            Instruction::Jg(number) => self.ins_jg(number)?,
-            Instruction::Jl(number) => self.ins_jl(number)?,
-            Instruction::Jmp(number) => self.ins_jmp(number)?,
-            Instruction::Cmp => self.ins_cmp()?,
-            Instruction::CmpStr => self.ins_cmp_str()?,
-            Instruction::Halt(exit_code) => self.halt = Some(exit_code),
-            Instruction::Nop => {}
-        };
+/// Error that ends a program at runtime along with the call stack at the point of failure. The
+/// message is the same as the underlying error, prefixed with the source line if it is known, eg.
+/// `line 14: Variable 'foo' does not exist.`
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub error: anyhow::Error,
+    /// Source line of the instruction that failed
+    pub line: Option<usize>,
+    /// Frames from the outermost (`MAIN`) to the innermost
+    pub backtrace: Vec<CallFrameInfo>,
+}
 
-        // If the previous instruction pointer is changed, then a jmp/ret or call instruction is
-        // called. Then don't change the ip.
-        if prev_ip == self.ip {
-            self.ip += 1;
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
         }
+        fmt::Display::fmt(&self.error, f)
+    }
+}
 
-        Ok(())
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
     }
+}
 
-    /// Adds a dynamic object to the current frame and pushes the object id to stack.
-    fn add_dynamic_object(&mut self, obj: Box<dyn Any>) {
-        let index = {
-            let mut stack_frame = self.frame_stack.last_mut().unwrap();
+impl RuntimeError {
+    /// Returns whether the program or the VM itself is at fault
+    pub fn category(&self) -> ErrorCategory {
+        if self.error.is::<InternalError>() {
+            ErrorCategory::Internal
+        } else {
+            ErrorCategory::Program
+        }
+    }
+}
 
-            stack_frame
-                .dynamic_objects
-                .insert(stack_frame.dyn_obj_index, obj);
+/// Kind of a `RuntimeError`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// The program did something invalid, eg. popped from an empty stack, read a variable that
+    /// does not exist or passed a handle that is not a dynamic object of the right kind. These are
+    /// meant to be reported to the user of the program.
+    Program,
+    /// An invariant of the VM is violated, eg. there is no frame to run in. This is a bug of the
+    /// VM or of a bytecode that is built by hand. Try regions do not catch these.
+    Internal,
+}
 
-            let index = stack_frame.dyn_obj_index;
-            stack_frame.dyn_obj_index += 1;
+/// Error of a violated VM invariant, see `ErrorCategory::Internal`
+#[derive(Debug)]
+struct InternalError(&'static str);
 
-            index
-        };
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for InternalError {}
+
+/// Where `CMP` and `CMP_STR` store their result for the conditional jumps
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CmpMode {
+    /// Push the result on stack as `-1`, `0` or `1` and pop it at the jump
+    #[default]
+    Stack,
+    /// Store the result in a flags register of the VM, so that values that are pushed or popped
+    /// between the comparison and the jump do not affect the branch
+    Flags,
+}
 
-        self.push_stack(index as i32);
+/// Error handler of a try region
+#[derive(Debug)]
+struct TryHandler {
+    handler: usize,     // instruction to jump to on error
+    sp: isize,          // stack pointer at the beginning of the region
+    frame_depth: usize, // number of frames at the beginning of the region
+}
+
+/// Error code that is pushed on stack when a runtime error is caught by a try region
+const CAUGHT_ERROR_CODE: i64 = 1;
+
+/// Default limit of the dynamic objects that are alive at the same time
+const DEFAULT_MAX_DYNAMIC_OBJECTS: usize = 1024;
+
+/// Default decoration of the output of the print built-ins
+const DEFAULT_PRINT_PREFIX: &str = ">>>>> ";
+
+/// Guard value that is put below the data of a string if string canaries are enabled
+const STR_CANARY: i64 = 0x5afe_c0de_5afe_c0de;
+
+/// Default limit of nested function calls
+const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// Virtual machine representation
+pub struct BciVm {
+    bytecode: Bytecode,
+    ip: usize,         // instruction pointer
+    sp: isize,         // stack pointer
+    fp: isize,         // frame pointer
+    halt: Option<i32>, // halt flag with exit code, see `exit_code`
+
+    stack: [i64; 1000],                // the general purpose stack
+    tags: [Param; 1000],               // kinds of the values, the length cell of a string is `Str`
+    frame_stack: Vec<StackFrame>,      // stack for `StackFrame`'s
+    stdin: Option<Box<dyn BufRead>>,   // overridden `READ_STDIN` source
+    arithmetic_mode: ArithmeticMode,   // overflow behaviour of arithmetic
+    writer: Box<dyn Write>,            // output of the program, stdout by default
+    trace: bool,                       // log every executed instruction to `writer`
+    max_call_depth: usize,             // limit of nested function calls
+    capabilities: Vec<Capability>,     // groups of built-in functions that can be called
+    args: Vec<String>,                 // arguments that are passed to the program
+    op_counts: [u64; OPCODE_COUNT],    // executions per `Instruction::opcode`
+    host_fns: HashMap<String, HostFn>, // functions that are registered by the embedder
+    root_jail: Option<PathBuf>,        // directory that the filesystem built-ins are confined to
+    cmp_mode: CmpMode,                 // where the comparison results are stored
+    cmp_flag: Option<Ordering>,        // result of the last comparison in `CmpMode::Flags`
+    inputs: HashMap<String, i64>,      // variables that are seeded into the frame of `MAIN`
+    try_handlers: Vec<TryHandler>,     // handlers of the active try regions, innermost on top
+    last_ret_value: Option<i64>,       // value of the last `RETURN_VALUE`, `None` after `RETURN`
+    sleep: SleepFn,                    // waits for `SLEEP_MS`, `thread::sleep` by default
+    max_dynamic_objects: usize,        // limit of the live dynamic objects of all frames
+    profile: Profile,                  // report that is written to `writer` on halt
+    profile_start: Option<Instant>,    // when the first instruction ran, for `Profile::Timed`
+    undefined_var_is_zero: bool,       // `READ_VAR` of a missing variable pushes `0`
+    print_prefix: String,              // written before every line the VM outputs
+    string_canaries: bool,             // put `STR_CANARY` below the data of every string
+    rng_state: u64,                    // state of the generator of `RANDOM`
+}
+
+impl BciVm {
+    pub fn load(program: &str) -> anyhow::Result<Self> {
+        let bytecode = Parser::new(program).parse()?;
+
+        Ok(Self::from_bytecode(bytecode))
     }
 
-    /// Returns a dynamic object with the id poped from the stack.
-    fn get_dynamic_object(&mut self) -> anyhow::Result<&mut Box<dyn Any>> {
-        let obj_ptr = self.pop_stack()?;
+    /// Reads the program from the file at `path` and loads it. Errors mention the path.
+    pub fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let program = fs::read_to_string(path)
+            .with_context(|| format!("failed to read '{}'", path.display()))?;
 
-        let stack_frame = self.frame_stack.last_mut().unwrap();
-        match stack_frame.dynamic_objects.get_mut(&(obj_ptr as usize)) {
-            Some(obj) => Ok(obj),
-            None => Err(anyhow!("fatal: cannot find the dynamic object".to_string())),
+        Self::load(&program).with_context(|| format!("failed to parse '{}'", path.display()))
+    }
+
+    /// Same as `load`, but only the built-in functions of the given `capabilities` can be called.
+    pub fn load_with_capabilities(
+        program: &str,
+        capabilities: &[Capability],
+    ) -> anyhow::Result<Self> {
+        let mut vm = Self::load(program)?;
+        vm.capabilities = capabilities.to_vec();
+
+        Ok(vm)
+    }
+
+    /// Same as `load`, but `vars` are seeded into the frame of `MAIN` when it is called, so the
+    /// program can read its inputs without loading them first.
+    pub fn with_inputs(program: &str, vars: HashMap<String, i64>) -> anyhow::Result<Self> {
+        let mut vm = Self::load(program)?;
+        vm.inputs = vars;
+
+        Ok(vm)
+    }
+
+    /// Creates a VM that runs an already parsed (and possibly optimized) bytecode.
+    pub fn from_bytecode(bytecode: Bytecode) -> Self {
+        BciVm {
+            bytecode,
+            ip: 0,
+            sp: -1,
+            fp: -1,
+            halt: None,
+            stack: [0; 1000],
+            tags: [Param::Number; 1000],
+            frame_stack: Vec::new(),
+            stdin: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            writer: Box::new(io::stdout()),
+            trace: false,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            capabilities: Capability::ALL.to_vec(),
+            args: Vec::new(),
+            op_counts: [0; OPCODE_COUNT],
+            host_fns: HashMap::new(),
+            root_jail: None,
+            cmp_mode: CmpMode::default(),
+            cmp_flag: None,
+            inputs: HashMap::new(),
+            try_handlers: Vec::new(),
+            last_ret_value: None,
+            sleep: Box::new(thread::sleep),
+            max_dynamic_objects: DEFAULT_MAX_DYNAMIC_OBJECTS,
+            profile: Profile::Off,
+            profile_start: None,
+            undefined_var_is_zero: false,
+            print_prefix: DEFAULT_PRINT_PREFIX.to_string(),
+            string_canaries: false,
+            rng_state: 0,
         }
     }
 
-    /// Pops a number and prints it to stdout.
-    fn built_in_print(&mut self) -> anyhow::Result<()> {
-        let data = self.pop_stack()?;
-        println!(">>>>> {}", data);
-        Ok(())
+    /// Sets where the program output goes. By default, stdout is used.
+    pub fn set_writer(&mut self, writer: Box<dyn Write>) {
+        self.writer = writer;
     }
 
-    /// Pops a string and prints it to stdout.
-    fn built_in_print_str(&mut self) -> anyhow::Result<()> {
-        let s = self.ins_pop_str()?;
-        println!(">>>>> {}", s);
-        Ok(())
+    /// Enables or disables tracing. When enabled, every instruction is written to the writer
+    /// together with its address and the top of the stack before it is executed.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
     }
 
-    /// Reads the file path from stack, and starts the read file process.
-    /// Saves and returns the line-by-line file iterator.
-    fn built_in_read_file(&mut self) -> anyhow::Result<()> {
-        let file_name = self.ins_pop_str()?;
+    /// Sets how arithmetic instructions behave on overflow. Default is `ArithmeticMode::Checked`.
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
 
-        let file = File::open(&file_name)?;
-        let lines = BufReader::new(file).lines();
+    /// Overrides the source that `READ_STDIN` reads from. By default, the process stdin is used.
+    pub fn set_stdin(&mut self, reader: Box<dyn BufRead>) {
+        self.stdin = Some(reader);
+    }
 
-        self.ins_push_str(&file_name)?;
-        self.add_dynamic_object(Box::new(lines));
+    /// Sets the maximum number of nested function calls. Calls beyond the limit fail instead of
+    /// growing the frame stack forever. Default is 1000.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
 
-        Ok(())
+    /// Sets the maximum number of dynamic objects (eg. iterators of `READ_FILE`) that can be alive
+    /// at the same time. Objects live until the function that created them returns, so this
+    /// bounds the open files of a program that opens them in a loop. Default is 1024.
+    pub fn set_max_dynamic_objects(&mut self, max_dynamic_objects: usize) {
+        self.max_dynamic_objects = max_dynamic_objects;
     }
 
-    /// Reads the next line and returns it.
-    fn built_in_read_file_next(&mut self) -> anyhow::Result<()> {
-        let line_iter = match self
-            .get_dynamic_object()?
-            .downcast_mut::<Lines<BufReader<File>>>()
-        {
-            Some(iter) => iter,
-            None => return Err(anyhow!("fatal: invalid dynamic object")),
-        };
+    /// Sets the arguments that the program can read with `GET_ARG`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
 
-        match line_iter.next() {
-            Some(line) => {
-                let line = line?;
-                self.ins_push_str(line.as_str())?;
-                self.push_stack(1); // For Some
+    /// Returns the counters of the instructions that are executed so far.
+    pub fn metrics(&self) -> ExecutionMetrics {
+        // The VM counts by opcode, the keywords are looked up in the bytecode that is executed
+        let mut per_instruction = HashMap::new();
+        for instruction in &self.bytecode.instructions {
+            let count = self.op_counts[instruction.opcode()];
+            if count > 0 {
+                per_instruction.insert(instruction.mnemonic(), count);
             }
-            None => self.push_stack(0), // For None
         }
 
-        Ok(())
+        ExecutionMetrics {
+            total_instructions: self.op_counts.iter().sum(),
+            per_instruction,
+        }
     }
 
-    /// Reads and returns information about the next file item (dir or file).
-    fn built_in_traverse_dir_next(&mut self) -> anyhow::Result<()> {
-        let dir_iter = match self.get_dynamic_object()?.downcast_mut::<fs::ReadDir>() {
-            Some(iter) => iter,
-            None => return Err(anyhow!("fatal: invalid dynamic object")),
-        };
+    /// Returns the exit code once the program halts, either by `HALT`, a failed `ASSERT_EQ` or the
+    /// return of `MAIN`. Returns `None` while the program has not halted, which is also the case
+    /// after `run` fails with an error.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.halt
+    }
 
-        match dir_iter.next() {
-            Some(entry) => {
-                let entry = entry?;
-                let path = entry.path();
+    /// Returns whether the program has halted. A halted program cannot be run again.
+    pub fn is_halted(&self) -> bool {
+        self.halt.is_some()
+    }
 
-                self.ins_push_str(path.to_str().unwrap())?;
-                if path.extension().is_some() {
-                    self.ins_push_str(path.extension().unwrap().to_str().unwrap())?;
-                } else {
-                    self.push_stack(0); // No extension
-                }
-                self.push_stack(entry.metadata()?.is_dir() as i32);
-                self.push_stack(1); // For Some
-            }
-            None => {
-                self.push_stack(0); // For None
-            }
-        }
+    /// Returns the frames of the running functions from the outermost (`MAIN`) to the innermost.
+    pub fn call_stack(&self) -> Vec<CallFrameInfo> {
+        self.frame_stack
+            .iter()
+            .map(|frame| {
+                let mut local_vars: Vec<String> = frame
+                    .local_vars
+                    .keys()
+                    .chain(frame.local_strs.keys())
+                    .cloned()
+                    .collect();
+                local_vars.sort();
 
-        Ok(())
+                CallFrameInfo {
+                    ret_addr: frame.ret_addr,
+                    local_vars,
+                }
+            })
+            .collect()
     }
 
-    /// Reads a directory path and starts a traverse directory process.
-    /// Returns the id for the directory iterator.
-    fn built_in_traverse_dir(&mut self) -> anyhow::Result<()> {
-        let dir_name = self.ins_pop_str()?;
-        let dir_iter = fs::read_dir(dir_name)?.into_iter();
+    /// Returns the value of the variable `name` in the frame of the currently running function.
+    /// Returns `None` if the variable does not exist or no function is running yet.
+    pub fn get_var(&self, name: &str) -> Option<i64> {
+        self.frame_stack.last()?.local_vars.get(name).copied()
+    }
 
-        self.add_dynamic_object(Box::new(dir_iter));
+    /// Creates/modifies the variable `name` in the frame of the currently running function.
+    /// Fails if no function is running yet, eg. before the first instruction (`CALL MAIN`) is
+    /// executed.
+    pub fn set_var(&mut self, name: &str, value: i64) -> anyhow::Result<()> {
+        self.current_frame_mut()?
+            .local_vars
+            .insert(name.to_string(), value);
 
         Ok(())
     }
 
-    /// Decrement the last value on stack
-    fn ins_decr(&mut self) -> anyhow::Result<()> {
-        let mut val = self.pop_stack()?;
-        val -= 1;
-        self.push_stack(val);
+    /// Registers `func` to be run when the program calls `name`. Host functions take precedence
+    /// over built-in and program functions, so they can also be used to mock built-in functions.
+    /// Capabilities do not apply to host functions.
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        func: impl FnMut(&mut BciVm) -> anyhow::Result<()> + 'static,
+    ) {
+        self.host_fns.insert(name.to_string(), Box::new(func));
+    }
 
+    /// Confines the paths of the filesystem built-in functions to `dir`. Relative paths are
+    /// resolved from `dir`, and paths that end up outside of it (eg. through `..` or symlinks)
+    /// are rejected with an error.
+    pub fn set_root_jail(&mut self, dir: PathBuf) -> anyhow::Result<()> {
+        self.root_jail = Some(dir.canonicalize()?);
         Ok(())
     }
 
-    /// Increment the last value on stack
-    fn ins_incr(&mut self) -> anyhow::Result<()> {
-        let mut val = self.pop_stack()?;
-        val += 1;
-        self.push_stack(val);
+    /// Sets where the comparison results are stored. Default is `CmpMode::Stack`.
+    pub fn set_cmp_mode(&mut self, mode: CmpMode) {
+        self.cmp_mode = mode;
+    }
 
-        Ok(())
+    /// Overrides how `SLEEP_MS` waits, eg. to record the durations in tests instead of actually
+    /// sleeping. By default, `std::thread::sleep` is used.
+    pub fn set_sleep(&mut self, sleep: impl FnMut(Duration) + 'static) {
+        self.sleep = Box::new(sleep);
     }
 
-    /// Compare two strings
-    fn ins_cmp_str(&mut self) -> anyhow::Result<()> {
-        let rhs = self.ins_pop_str()?;
-        let lhs = self.ins_pop_str()?;
+    /// Enables a report of the executed instructions that is written to the writer once the
+    /// program halts. Default is `Profile::Off`.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+    }
 
-        if lhs == rhs {
-            self.push_stack(0);
-        } else if lhs > rhs {
-            self.push_stack(1);
-        } else {
-            self.push_stack(-1);
-        }
+    /// Makes `READ_VAR` of a variable that does not exist push `0` instead of failing, which is
+    /// handy for quick prototyping. Disabled by default.
+    pub fn set_undefined_var_is_zero(&mut self, undefined_var_is_zero: bool) {
+        self.undefined_var_is_zero = undefined_var_is_zero;
+    }
 
-        Ok(())
+    /// Sets the text that is written before every line that the VM writes to the writer, eg. the
+    /// output of `PRINT`, `DUMP_STACK` or the profile. `PRINT_RAW` and tracing are not decorated.
+    /// Default is `">>>>> "`, and an empty prefix disables the decoration.
+    pub fn set_print_prefix(&mut self, prefix: String) {
+        self.print_prefix = prefix;
     }
 
-    /// Compare two numbers
-    fn ins_cmp(&mut self) -> anyhow::Result<()> {
-        let rhs = self.pop_stack()?;
-        let lhs = self.pop_stack()?;
+    /// Enables a canary cell below the data of every string that is pushed, which separates it
+    /// from the values that are pushed before. The canary is checked whenever a string is read,
+    /// so a corrupted length fails with an error instead of silently reading the wrong cells.
+    /// Meant for debugging, since strings occupy an additional cell. Must be set before any
+    /// string is pushed. Disabled by default.
+    pub fn set_string_canaries(&mut self, string_canaries: bool) {
+        self.string_canaries = string_canaries;
+    }
 
-        if lhs == rhs {
-            self.push_stack(0);
-        } else if lhs > rhs {
-            self.push_stack(1);
-        } else {
-            self.push_stack(-1);
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        if self.halt.is_some() {
+            return Err(anyhow!("Program is already ended."));
         }
 
+        while self.halt.is_none() {
+            self.next_instruction()?;
+        }
         Ok(())
     }
 
-    /// Jump if two numbers are equal
-    fn ins_je(&mut self, count: i32) -> anyhow::Result<()> {
-        if self.pop_stack()? != 0 {
-            return Ok(());
+    /// Parses a single instruction and runs it immediately against the current state, so that
+    /// a session can be built line-by-line. A VM that is created with
+    /// `BciVm::from_bytecode(Bytecode::default())` can be used to start an empty session.
+    ///
+    /// Variables of the session live in a frame that is created by the first line if no function
+    /// is running. Calling a function of the program runs it until it returns. Jumps and returns
+    /// cannot be evaluated on their own.
+    pub fn eval_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let instruction = Parser::new(line).parse_line()?;
+        if !instruction.jump_offsets().is_empty()
+            || matches!(
+                instruction,
+                Instruction::Ret | Instruction::RetValue | Instruction::RetValues(_)
+            )
+        {
+            return Err(anyhow!(
+                "{} cannot be evaluated on its own",
+                instruction.mnemonic()
+            ));
         }
 
-        self.ins_jmp(count)
-    }
+        if self.frame_stack.is_empty() {
+            self.frame_stack.push(StackFrame::new(self.ip));
+            self.fp += 1;
+        }
 
-    /// Jump if two numbers are not equal
-    fn ins_jne(&mut self, count: i32) -> anyhow::Result<()> {
-        if self.pop_stack()? == 0 {
-            return Ok(());
+        let depth = self.frame_stack.len();
+        let ip = self.ip;
+        let result = self.execute(instruction).and_then(|_| {
+            while self.frame_stack.len() > depth && self.halt.is_none() {
+                self.next_instruction()?;
+            }
+            Ok(())
+        });
+
+        // A failed call leaves its frames behind, so the session is brought back to its frame
+        if result.is_err() {
+            self.frame_stack.truncate(depth);
+            self.fp = depth as isize - 1;
+            self.try_handlers
+                .retain(|try_handler| try_handler.frame_depth <= depth);
         }
+        self.ip = ip;
 
-        self.ins_jmp(count)
+        result
     }
 
-    /// Jump if the first number is greater
-    fn ins_jg(&mut self, count: i32) -> anyhow::Result<()> {
-        if self.pop_stack()? != 1 {
+    /// Same as `run`, but fails with a `TimeoutError` if the program runs longer than `timeout`.
+    /// The clock is checked periodically, so the program may run slightly longer than `timeout`.
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        if self.halt.is_some() {
+            return Err(anyhow!("Program is already ended."));
+        }
+
+        let start = Instant::now();
+        let mut executed: u64 = 0;
+        while self.halt.is_none() {
+            self.next_instruction()?;
+
+            executed += 1;
+            if executed.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && start.elapsed() > timeout {
+                return Err(TimeoutError { timeout }.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_instruction(&mut self) -> anyhow::Result<()> {
+        // Running past the last instruction (eg. `MAIN` with an empty body) ends the program as if
+        // `HALT 0` was executed.
+        if self.ip >= self.bytecode.instructions.len() {
+            self.halt = Some(0);
+            return self.write_profile();
+        }
+
+        if self.profile == Profile::Timed && self.profile_start.is_none() {
+            self.profile_start = Some(Instant::now());
+        }
+
+        // Cloning an `Instruction` is cheap since its strings are reference counted. The borrow of
+        // the bytecode ends here, leaving `self` free for the mutable dispatch below.
+        let instruction = self.bytecode.instructions[self.ip].clone();
+
+        if self.trace {
+            self.trace_instruction(&instruction)?;
+        }
+
+        self.op_counts[instruction.opcode()] += 1;
+
+        let prev_ip = self.ip;
+        if let Err(err) = self.execute(instruction) {
+            return self.catch_error(err);
+        }
+
+        // If the previous instruction pointer is changed, then a jmp/ret or call instruction is
+        // called. Then don't change the ip.
+        if prev_ip == self.ip {
+            self.ip += 1;
+        }
+
+        if self.halt.is_some() {
+            self.write_profile()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the report of `Profile`. The instructions are sorted by their counts.
+    fn write_profile(&mut self) -> anyhow::Result<()> {
+        if self.profile == Profile::Off {
             return Ok(());
         }
 
-        self.ins_jmp(count)
+        let metrics = self.metrics();
+        let mut counts: Vec<(&&str, &u64)> = metrics.per_instruction.iter().collect();
+        counts.sort_unstable_by(|lhs, rhs| rhs.1.cmp(lhs.1).then(lhs.0.cmp(rhs.0)));
+
+        let mut report = format!(
+            "{}profile: {} instructions\n",
+            self.print_prefix, metrics.total_instructions
+        );
+        for (mnemonic, count) in counts {
+            report.push_str(&format!("{}  {}: {}\n", self.print_prefix, mnemonic, count));
+        }
+        if let Some(start) = self.profile_start {
+            report.push_str(&format!(
+                "{}time: {:?}\n",
+                self.print_prefix,
+                start.elapsed()
+            ));
+        }
+
+        self.writer.write_all(report.as_bytes())?;
+        Ok(())
     }
 
-    /// Jump if the first number is less
-    fn ins_jl(&mut self, count: i32) -> anyhow::Result<()> {
-        if self.pop_stack()? != -1 {
+    /// Runs `instruction` against the current state. Jumps, calls and returns only set the
+    /// instruction pointer, the caller decides how to continue.
+    fn execute(&mut self, instruction: Instruction) -> anyhow::Result<()> {
+        match instruction {
+            Instruction::Call(fn_name) => self.ins_call(&fn_name)?,
+            Instruction::RetValue => self.ins_ret_value()?,
+            Instruction::Ret => self.ins_ret()?,
+            Instruction::Mul => self.ins_mul()?,
+            Instruction::Add => self.ins_add()?,
+            Instruction::Sub => self.ins_sub()?,
+            Instruction::Incr => self.ins_incr()?,
+            Instruction::Decr => self.ins_decr()?,
+            Instruction::LoadVal(number) => self.ins_load_val(number)?,
+            Instruction::ReadVar(var_name) => self.ins_read_var(&var_name)?,
+            Instruction::WriteVar(var_name) => self.ins_write_var(&var_name)?,
+            Instruction::PushStr(s) => self.ins_push_str(&s)?,
+            Instruction::PopStr => {
+                let _ = self.ins_pop_str()?;
+            }
+            Instruction::Je(number) => self.ins_je(number)?,
+            Instruction::Jne(number) => self.ins_jne(number)?,
+            Instruction::Jg(number) => self.ins_jg(number)?,
+            Instruction::Jl(number) => self.ins_jl(number)?,
+            Instruction::Jmp(number) => self.ins_jmp(number)?,
+            Instruction::Cmp => self.ins_cmp()?,
+            Instruction::CmpStr => self.ins_cmp_str()?,
+            Instruction::Halt(exit_code) => self.halt = Some(exit_code),
+            Instruction::Jz(number) => self.ins_jz(number)?,
+            Instruction::Jnz(number) => self.ins_jnz(number)?,
+            Instruction::Sign => self.ins_sign()?,
+            Instruction::Min => self.ins_min()?,
+            Instruction::Max => self.ins_max()?,
+            Instruction::Abs => self.ins_abs()?,
+            Instruction::Pow => self.ins_pow()?,
+            Instruction::JeStr(number) => self.ins_je_str(number)?,
+            Instruction::JneStr(number) => self.ins_jne_str(number)?,
+            Instruction::WriteStrVar(var_name) => self.ins_write_str_var(&var_name)?,
+            Instruction::ReadStrVar(var_name) => self.ins_read_str_var(&var_name)?,
+            Instruction::DropN(count) => self.ins_drop_n(count)?,
+            Instruction::ReadVarOr(var_name, default) => {
+                self.ins_read_var_or(&var_name, default)?
+            }
+            Instruction::AndB => self.ins_and_b()?,
+            Instruction::OrB => self.ins_or_b()?,
+            Instruction::RetValues(count) => self.ins_ret_values(count)?,
+            Instruction::AssertEq => self.ins_assert_eq()?,
+            Instruction::Jge(number) => self.ins_jge(number)?,
+            Instruction::Jle(number) => self.ins_jle(number)?,
+            Instruction::Print => self.ins_call("PRINT")?,
+            Instruction::AddImm(number) => self.ins_add_imm(number)?,
+            Instruction::SubImm(number) => self.ins_sub_imm(number)?,
+            Instruction::Bool => self.ins_bool()?,
+            Instruction::SwapVar(lhs, rhs) => self.ins_swap_var(&lhs, &rhs)?,
+            Instruction::StackSize => self.push_stack(self.sp as i64 + 1)?,
+            Instruction::JeqImm(imm, offset) => self.ins_jeq_imm(imm, offset)?,
+            Instruction::TryBegin(count) => self.ins_try_begin(count)?,
+            Instruction::TryEnd => self.ins_try_end()?,
+            Instruction::TeeVar(var_name) => self.ins_tee_var(&var_name)?,
+            Instruction::Exit => self.ins_exit()?,
+            Instruction::Cmp3 => self.ins_cmp3()?,
+            Instruction::LoadConstStr(index) => self.ins_load_const_str(index)?,
+            Instruction::Pick(index) => self.ins_pick(index)?,
+            Instruction::Switch(offsets) => self.ins_switch(&offsets)?,
+            Instruction::CallRel(count) => self.ins_call_rel(count)?,
+            Instruction::LoadRet => self.ins_load_ret()?,
+            Instruction::WriteUpvar(var_name) => self.ins_write_upvar(&var_name)?,
+            Instruction::ReadUpvar(var_name) => self.ins_read_upvar(&var_name)?,
+            Instruction::CmpStrCi => self.ins_cmp_str_ci()?,
+            Instruction::Nop => {}
+        }
+
+        Ok(())
+    }
+
+    /// Jumps to the handler of the innermost try region that is still alive with the error code on
+    /// stack. The stack and the frames are unwound to the beginning of the region. Returns the
+    /// error as a `RuntimeError` with the current backtrace if there is no such region.
+    fn catch_error(&mut self, err: anyhow::Error) -> anyhow::Result<()> {
+        // Bugs of the VM are not for the program to handle
+        let catchable = !err.is::<InternalError>();
+        while let Some(try_handler) = self.try_handlers.pop() {
+            // Regions of the functions that have already returned are dropped as well
+            if !catchable || try_handler.frame_depth > self.frame_stack.len() {
+                continue;
+            }
+
+            self.frame_stack.truncate(try_handler.frame_depth);
+            self.fp = self.frame_stack.len() as isize - 1;
+            self.sp = try_handler.sp;
+            self.push_stack(CAUGHT_ERROR_CODE)?;
+            self.ip = try_handler.handler;
+
             return Ok(());
         }
 
-        self.ins_jmp(count)
+        // Errors of nested runs (eg. a host function that runs the VM) already have a backtrace
+        if err.is::<RuntimeError>() {
+            return Err(err);
+        }
+
+        Err(RuntimeError {
+            error: err,
+            line: self.bytecode.source_line(self.ip),
+            backtrace: self.call_stack(),
+        }
+        .into())
     }
 
-    /// Jump to a location
-    fn ins_jmp(&mut self, count: i32) -> anyhow::Result<()> {
-        if count > self.ip as i32 {
-            return Err(anyhow!("Invalid jump."));
+    /// Writes the instruction that is about to run along with the current stack top.
+    fn trace_instruction(&mut self, instruction: &Instruction) -> anyhow::Result<()> {
+        let top = if self.sp < 0 {
+            "empty".to_string()
+        } else {
+            self.stack[self.sp as usize].to_string()
+        };
+
+        writeln!(
+            self.writer,
+            "[trace] {:04}: {:?} (top: {})",
+            self.ip, instruction, top
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the built-in function named `fn_name` if there is any.
+    fn builtin_fn(fn_name: &str) -> Option<Builtin> {
+        // `Str` is the kind of a parameter here
+        use Capability::{Fs, Io, Math, Str as Strings};
+        use Param::*;
+
+        let (capability, func, params): (Capability, BuiltinFn, &'static [Param]) = match fn_name {
+            "TRAVERSE_DIR" => (Fs, Self::built_in_traverse_dir, &[Str]),
+            "TRAVERSE_DIR_SORTED" => (Fs, Self::built_in_traverse_dir_sorted, &[Str]),
+            "TRAVERSE_DIR_RECURSIVE" => (Fs, Self::built_in_traverse_dir_recursive, &[Str]),
+            "TRAVERSE_DIR_NEXT" => (Fs, Self::built_in_traverse_dir_next, &[Number]),
+            "READ_FILE" => (Fs, Self::built_in_read_file, &[Str]),
+            "READ_FILE_ALL" => (Fs, Self::built_in_read_file_all, &[Str]),
+            "READ_FILE_NEXT" => (Fs, Self::built_in_read_file_next, &[Number]),
+            "WRITE_FILE" => (Fs, Self::built_in_write_file, &[Str, Str]),
+            "APPEND_FILE" => (Fs, Self::built_in_append_file, &[Str, Str]),
+            "FILE_SIZE" => (Fs, Self::built_in_file_size, &[Str]),
+            "FILE_MODIFIED" => (Fs, Self::built_in_file_modified, &[Str]),
+            "GET_CWD" => (Fs, Self::built_in_get_cwd, &[]),
+            "READ_STDIN" => (Io, Self::built_in_read_stdin, &[]),
+            "PRINT" => (Io, Self::built_in_print, &[Number]),
+            "PRINT_RAW" => (Io, Self::built_in_print_raw, &[Number]),
+            "PRINT_HEX" => (Io, Self::built_in_print_hex, &[Number]),
+            "PRINT_STR" => (Io, Self::built_in_print_str, &[Str]),
+            "PRINT_STR_PEEK" => (Io, Self::built_in_print_str_peek, &[Str]),
+            "GET_ARG" => (Io, Self::built_in_get_arg, &[Number]),
+            "DUMP_VARS" => (Io, Self::built_in_dump_vars, &[]),
+            "SLEEP_MS" => (Io, Self::built_in_sleep_ms, &[Number]),
+            "DUMP_STACK" => (Io, Self::built_in_dump_stack, &[]),
+            "STR_SLICE" => (Strings, Self::built_in_str_slice, &[Str, Number, Number]),
+            "STR_SPLIT" => (Strings, Self::built_in_str_split, &[Str, Str]),
+            "STR_SPLIT_NEXT" => (Strings, Self::built_in_str_split_next, &[Number]),
+            "HASH_STR" => (Strings, Self::built_in_hash_str, &[Str]),
+            "RANDOM" => (Math, Self::built_in_random, &[Number]),
+            "SEED_RANDOM" => (Math, Self::built_in_seed_random, &[Number]),
+            "HAS_EXTENSION" => (Strings, Self::built_in_has_extension, &[Str, Str]),
+            "FORMAT_INT" => (
+                Strings,
+                Self::built_in_format_int,
+                &[Number, Number, Number],
+            ),
+            "STR_FIND" => (Strings, Self::built_in_str_find, &[Str, Str]),
+            _ => return None,
+        };
+
+        Some(Builtin {
+            capability,
+            func,
+            params,
+        })
+    }
+
+    /// Checks that the stack has the shape `params` describe, so that built-in functions fail
+    /// with a descriptive error instead of reading garbage.
+    fn check_params(&self, fn_name: &str, params: &[Param]) -> anyhow::Result<()> {
+        let mismatch = || {
+            let names: Vec<&str> = params.iter().map(Param::describe).collect();
+            let expected = match names.split_last() {
+                Some((last, [])) => last.to_string(),
+                Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+                None => "nothing".to_string(),
+            };
+            anyhow!("{} expects {} on the stack", fn_name, expected)
+        };
+
+        let mut top = self.sp;
+        for param in params.iter().rev() {
+            if top < 0 {
+                return Err(mismatch());
+            }
+
+            top -= match param {
+                Param::Number => 1,
+                Param::Str => {
+                    let str_len = self.stack[top as usize];
+                    if str_len < 0 {
+                        return Err(mismatch());
+                    }
+                    self.str_cells(str_len)
+                }
+            };
+
+            if top < -1 {
+                return Err(mismatch());
+            }
         }
 
-        let new_ip = ((self.ip as i32) - count) as usize;
-        if new_ip >= self.bytecode.instructions.len() {
-            Err(anyhow!("Invalid jump."))
+        Ok(())
+    }
+
+    /// Resolves a path that is given to a filesystem built-in function according to the root
+    /// jail. Paths are used as is if there is no root jail.
+    fn resolve_path(&self, path: &str) -> anyhow::Result<PathBuf> {
+        let root = match &self.root_jail {
+            Some(root) => root,
+            None => return Ok(PathBuf::from(path)),
+        };
+
+        let joined = root.join(path);
+        // A file that does not exist yet (eg. for `WRITE_FILE`) is resolved through its parent.
+        // Anything that exists, including dangling symlinks, must be resolvable by itself.
+        let resolved = if fs::symlink_metadata(&joined).is_ok() {
+            joined.canonicalize()?
         } else {
-            self.ip = new_ip;
-            Ok(())
+            match (joined.parent(), joined.file_name()) {
+                (Some(parent), Some(file_name)) => parent.canonicalize()?.join(file_name),
+                _ => return Err(anyhow!("cannot resolve path '{}'", path)),
+            }
+        };
+
+        if !resolved.starts_with(root) {
+            return Err(anyhow!("path '{}' is outside of the root jail", path));
         }
+
+        Ok(resolved)
     }
 
-    /// Pop a value from stack and write it to variables of the current frame
-    fn ins_write_var(&mut self, var_name: &str) -> anyhow::Result<()> {
-        let value = self.pop_stack()?;
-        let local_vars = &mut self.frame_stack[self.fp as usize].local_vars;
+    /// Returns the frame of the function that is currently running.
+    fn current_frame(&self) -> anyhow::Result<&StackFrame> {
+        debug_assert_eq!(self.fp, self.frame_stack.len() as isize - 1);
 
-        if let Some(old_value) = local_vars.get_mut(var_name) {
-            *old_value = value;
+        match self.frame_stack.last() {
+            Some(frame) => Ok(frame),
+            None => Err(InternalError("fatal: there is no stack frame").into()),
+        }
+    }
+
+    /// Returns the frame of the function that is currently running as mutable.
+    fn current_frame_mut(&mut self) -> anyhow::Result<&mut StackFrame> {
+        debug_assert_eq!(self.fp, self.frame_stack.len() as isize - 1);
+
+        match self.frame_stack.last_mut() {
+            Some(frame) => Ok(frame),
+            None => Err(InternalError("fatal: there is no stack frame").into()),
+        }
+    }
+
+    /// Returns the frame of the function that called the currently running one as mutable.
+    fn caller_frame_mut(&mut self) -> anyhow::Result<&mut StackFrame> {
+        match self.frame_stack.len().checked_sub(2) {
+            Some(caller) => Ok(&mut self.frame_stack[caller]),
+            None => Err(anyhow!("There is no caller frame.")),
+        }
+    }
+
+    /// Adds a dynamic object to the current frame and pushes the object id to stack.
+    fn add_dynamic_object(&mut self, obj: Box<dyn Any>) -> anyhow::Result<()> {
+        let live_objects: usize = self
+            .frame_stack
+            .iter()
+            .map(|frame| frame.dynamic_objects.len())
+            .sum();
+        if live_objects >= self.max_dynamic_objects {
+            return Err(anyhow!(
+                "too many dynamic objects, the limit is {}",
+                self.max_dynamic_objects
+            ));
+        }
+
+        let index = {
+            let stack_frame = self.current_frame_mut()?;
+
+            stack_frame
+                .dynamic_objects
+                .insert(stack_frame.dyn_obj_index, obj);
+
+            let index = stack_frame.dyn_obj_index;
+            stack_frame.dyn_obj_index += 1;
+
+            index
+        };
+
+        self.push_stack(index as i64)?;
+
+        Ok(())
+    }
+
+    /// Returns a dynamic object with the id poped from the stack.
+    fn get_dynamic_object(&mut self) -> anyhow::Result<&mut Box<dyn Any>> {
+        let obj_ptr = self.pop_stack()?;
+
+        let stack_frame = self.current_frame_mut()?;
+        match stack_frame.dynamic_objects.get_mut(&(obj_ptr as usize)) {
+            Some(obj) => Ok(obj),
+            None => Err(anyhow!("cannot find the dynamic object {}", obj_ptr)),
+        }
+    }
+
+    /// Pops a number and prints it to the writer.
+    fn built_in_print(&mut self) -> anyhow::Result<()> {
+        let data = self.pop_stack()?;
+        writeln!(self.writer, "{}{}", self.print_prefix, data)?;
+        Ok(())
+    }
+
+    /// Pops a number and prints it to the writer without any decoration.
+    fn built_in_print_raw(&mut self) -> anyhow::Result<()> {
+        let data = self.pop_stack()?;
+        writeln!(self.writer, "{}", data)?;
+        Ok(())
+    }
+
+    /// Pops a number and prints it in hexadecimal to the writer.
+    fn built_in_print_hex(&mut self) -> anyhow::Result<()> {
+        let data = self.pop_stack()?;
+        writeln!(self.writer, "{}{:#x}", self.print_prefix, data)?;
+        Ok(())
+    }
+
+    /// Pops a string and prints it to the writer.
+    fn built_in_print_str(&mut self) -> anyhow::Result<()> {
+        let s = self.ins_pop_str()?;
+        writeln!(self.writer, "{}{}", self.print_prefix, s)?;
+        Ok(())
+    }
+
+    /// Prints the string on top of stack to the writer and leaves it on stack.
+    fn built_in_print_str_peek(&mut self) -> anyhow::Result<()> {
+        let s = self.peek_str()?;
+        writeln!(self.writer, "{}{}", self.print_prefix, s)?;
+        Ok(())
+    }
+
+    /// Prints the whole stack from bottom to top without modifying it.
+    fn built_in_dump_stack(&mut self) -> anyhow::Result<()> {
+        let stack = &self.stack[..(self.sp + 1) as usize];
+        writeln!(self.writer, "{}stack: {:?}", self.print_prefix, stack)?;
+        Ok(())
+    }
+
+    /// Pops an index and returns the argument at that index if there is any.
+    fn built_in_get_arg(&mut self) -> anyhow::Result<()> {
+        let index = self.pop_stack()?;
+
+        match usize::try_from(index)
+            .ok()
+            .and_then(|index| self.args.get(index))
+        {
+            Some(arg) => {
+                let arg = arg.clone();
+                self.ins_push_str(&arg)?;
+                self.push_stack(1)?; // For Some
+            }
+            None => self.push_stack(0)?, // For None
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the directory that relative paths are resolved against, which is the root jail if
+    /// there is any and the current working directory otherwise.
+    fn built_in_get_cwd(&mut self) -> anyhow::Result<()> {
+        let cwd = match &self.root_jail {
+            Some(root) => Some(root.clone()),
+            None => std::env::current_dir().ok(),
+        };
+
+        match cwd.as_deref().and_then(Path::to_str) {
+            Some(cwd) => {
+                let cwd = cwd.to_string();
+                self.ins_push_str(&cwd)?;
+                self.push_stack(1)?; // For Some
+            }
+            None => self.push_stack(0)?, // For None
+        }
+
+        Ok(())
+    }
+
+    /// Prints the variables of the current frame sorted by their names without modifying them.
+    fn built_in_dump_vars(&mut self) -> anyhow::Result<()> {
+        let mut vars: Vec<(&String, &i64)> = self.current_frame()?.local_vars.iter().collect();
+        vars.sort_unstable();
+
+        let dump: String = vars
+            .into_iter()
+            .map(|(name, value)| format!("{}{} = {}\n", self.print_prefix, name, value))
+            .collect();
+        self.writer.write_all(dump.as_bytes())?;
+        Ok(())
+    }
+
+    /// Pops a number of milliseconds and waits that long
+    fn built_in_sleep_ms(&mut self) -> anyhow::Result<()> {
+        let millis = self.pop_stack()?;
+        let millis =
+            u64::try_from(millis).map_err(|_| anyhow!("SLEEP_MS: negative duration {}", millis))?;
+        (self.sleep)(Duration::from_millis(millis));
+
+        Ok(())
+    }
+
+    /// Pops a string and pushes its 32-bit FNV-1a hash. The hash is computed in-crate so that it
+    /// is the same on every platform and Rust version.
+    fn built_in_hash_str(&mut self) -> anyhow::Result<()> {
+        let s = self.ins_pop_str()?;
+        self.push_stack(fnv1a_32(s.as_bytes()) as i64)?;
+
+        Ok(())
+    }
+
+    /// Pops the padding flag, the width and a number, then pushes the number as a decimal string
+    /// that is padded from the left to `width`. Zeros are used for a nonzero flag, spaces
+    /// otherwise.
+    fn built_in_format_int(&mut self) -> anyhow::Result<()> {
+        let zero_pad = self.pop_stack()? != 0;
+        let width = self.pop_stack()?;
+        let number = self.pop_stack()?;
+
+        // A wider string would not fit in the stack anyway
+        let width = match usize::try_from(width) {
+            Ok(width) if width <= self.stack.len() * CELL_SIZE => width,
+            _ => return Err(anyhow!("FORMAT_INT width {} is out of range", width)),
+        };
+
+        let s = if zero_pad {
+            format!("{:0width$}", number, width = width)
         } else {
-            local_vars.insert(var_name.to_string(), value);
+            format!("{:>width$}", number, width = width)
+        };
+        self.ins_push_str(&s)
+    }
+
+    /// Pops an extension and a path, then pushes `1` if the path ends with the extension ignoring
+    /// ASCII case, `0` otherwise. The leading `.` of the extension is optional.
+    fn built_in_has_extension(&mut self) -> anyhow::Result<()> {
+        let extension = self.ins_pop_str()?;
+        let path = self.ins_pop_str()?;
+
+        let extension = extension.strip_prefix('.').unwrap_or(&extension).as_bytes();
+        let path = path.as_bytes();
+        let matches = !extension.is_empty()
+            && path.len() > extension.len()
+            && path[path.len() - extension.len() - 1] == b'.'
+            && path[path.len() - extension.len()..].eq_ignore_ascii_case(extension);
+        self.push_stack(matches as i64)?;
+
+        Ok(())
+    }
+
+    /// Pops a bound and pushes a pseudo-random number in `[0, bound)`. The numbers are generated
+    /// in-crate, so a seed gives the same sequence on every platform.
+    fn built_in_random(&mut self) -> anyhow::Result<()> {
+        let bound = self.pop_stack()?;
+        if bound <= 0 {
+            return Err(anyhow!("RANDOM expects a positive bound, got {}", bound));
         }
 
+        // Numbers above the largest multiple of the bound are skipped, so that every result is
+        // equally likely
+        let bound = bound as u64;
+        let zone = u64::MAX - u64::MAX % bound;
+        let value = loop {
+            let value = splitmix64(&mut self.rng_state);
+            if value < zone {
+                break value % bound;
+            }
+        };
+        self.push_stack(value as i64)?;
+
         Ok(())
     }
 
-    /// Load a variable from frame to stack
-    fn ins_read_var(&mut self, var_name: &str) -> anyhow::Result<()> {
-        match self.frame_stack[self.fp as usize].local_vars.get(var_name) {
-            Some(&var) => {
-                self.push_stack(var);
-                Ok(())
+    /// Pops a seed and restarts the generator of `RANDOM` from it
+    fn built_in_seed_random(&mut self) -> anyhow::Result<()> {
+        self.rng_state = self.pop_stack()? as u64;
+
+        Ok(())
+    }
+
+    /// Pops the needle and the haystack, then pushes the byte index of the first occurrence of the
+    /// needle, or `-1` if there is none. Both strings are consumed.
+    fn built_in_str_find(&mut self) -> anyhow::Result<()> {
+        let needle = self.ins_pop_str()?;
+        let haystack = self.ins_pop_str()?;
+
+        let index = haystack.find(&needle).map_or(-1, |index| index as i64);
+        self.push_stack(index)?;
+
+        Ok(())
+    }
+
+    /// Pops the end index, the start index and a string, then pushes the substring between the
+    /// indices. Indices are in bytes and `end` is exclusive.
+    fn built_in_str_slice(&mut self) -> anyhow::Result<()> {
+        let end = self.pop_stack()?;
+        let start = self.pop_stack()?;
+        let s = self.ins_pop_str()?;
+
+        let slice = usize::try_from(start)
+            .ok()
+            .zip(usize::try_from(end).ok())
+            .and_then(|(start, end)| s.get(start..end));
+        match slice {
+            Some(slice) => {
+                let slice = slice.to_string();
+                self.ins_push_str(&slice)
             }
-            None => Err(anyhow!("Variable '{}' does not exist.", var_name)),
+            None => Err(anyhow!(
+                "STR_SLICE: {}..{} is out of bounds for a string of length {}",
+                start,
+                end,
+                s.len()
+            )),
+        }
+    }
+
+    /// Pops the delimiter and the string, then splits the string by the delimiter.
+    /// Saves and returns the iterator over the pieces.
+    fn built_in_str_split(&mut self) -> anyhow::Result<()> {
+        let delimiter = self.ins_pop_str()?;
+        let s = self.ins_pop_str()?;
+
+        if delimiter.is_empty() {
+            return Err(anyhow!("STR_SPLIT expects a non-empty delimiter"));
         }
+
+        let pieces: SplitIter = s
+            .split(delimiter.as_str())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        self.add_dynamic_object(Box::new(pieces))?;
+
+        Ok(())
+    }
+
+    /// Returns the next piece of a split string.
+    fn built_in_str_split_next(&mut self) -> anyhow::Result<()> {
+        let pieces = match self.get_dynamic_object()?.downcast_mut::<SplitIter>() {
+            Some(iter) => iter,
+            None => return Err(anyhow!("invalid dynamic object")),
+        };
+
+        match pieces.next() {
+            Some(piece) => {
+                self.ins_push_str(&piece)?;
+                self.push_stack(1)?; // For Some
+            }
+            None => self.push_stack(0)?, // For None
+        }
+
+        Ok(())
+    }
+
+    /// Reads the file path from stack, and starts the read file process.
+    /// Saves and returns the line-by-line file iterator.
+    fn built_in_read_file(&mut self) -> anyhow::Result<()> {
+        let file_name = self.ins_pop_str()?;
+
+        let file = File::open(self.resolve_path(&file_name)?).map_err(|source| BciError::Io {
+            path: file_name.clone(),
+            source,
+        })?;
+        let reader: Box<dyn BufRead> = Box::new(BufReader::new(file));
+        let lines: LineIter = reader.lines();
+
+        self.ins_push_str(&file_name)?;
+        self.add_dynamic_object(Box::new(lines))?;
+
+        Ok(())
+    }
+
+    /// Pops a path and pushes the whole content of the file as a single string and `1`. Pushes
+    /// only `0` if the file cannot be read, is not valid UTF-8 or does not fit in the stack.
+    fn built_in_read_file_all(&mut self) -> anyhow::Result<()> {
+        let file_name = self.ins_pop_str()?;
+        let path = self.resolve_path(&file_name)?;
+
+        // Data cells, the length cell and the flag should fit in the stack. The size is checked
+        // before reading, so that a huge file is not loaded into memory for nothing.
+        let free_cells = self.stack.len() as isize - self.sp - 1;
+        let fits = |len: u64| self.str_cells(len as i64) < free_cells;
+        let content = fs::metadata(&path)
+            .ok()
+            .filter(|metadata| fits(metadata.len()))
+            .and_then(|_| fs::read_to_string(&path).ok())
+            .filter(|content| fits(content.len() as u64));
+
+        match content {
+            Some(content) => {
+                self.ins_push_str(&content)?;
+                self.push_stack(1)?; // For Some
+            }
+            None => self.push_stack(0)?, // For None
+        }
+
+        Ok(())
+    }
+
+    /// Starts reading the stdin (or the source set by `set_stdin`) line-by-line.
+    /// Returns the iterator which can be consumed by `READ_FILE_NEXT`.
+    fn built_in_read_stdin(&mut self) -> anyhow::Result<()> {
+        let reader = match self.stdin.take() {
+            Some(reader) => reader,
+            None => Box::new(BufReader::new(io::stdin())),
+        };
+        let lines: LineIter = reader.lines();
+
+        self.add_dynamic_object(Box::new(lines))?;
+
+        Ok(())
+    }
+
+    /// Reads the next line and returns it.
+    fn built_in_read_file_next(&mut self) -> anyhow::Result<()> {
+        let line_iter = match self.get_dynamic_object()?.downcast_mut::<LineIter>() {
+            Some(iter) => iter,
+            None => return Err(anyhow!("invalid dynamic object")),
+        };
+
+        match line_iter.next() {
+            Some(line) => {
+                let line = line?;
+                self.ins_push_str(line.as_str())?;
+                self.push_stack(1)?; // For Some
+            }
+            None => self.push_stack(0)?, // For None
+        }
+
+        Ok(())
+    }
+
+    /// Pops the content and the file path, then writes the content to the file. The file is
+    /// created if it does not exist and truncated otherwise. Pushes `1` on success, else `0`.
+    fn built_in_write_file(&mut self) -> anyhow::Result<()> {
+        let content = self.ins_pop_str()?;
+        let file_name = self.ins_pop_str()?;
+        let path = self.resolve_path(&file_name)?;
+
+        let written = fs::write(path, content).is_ok();
+        self.push_stack(written as i64)?;
+
+        Ok(())
+    }
+
+    /// Same as `WRITE_FILE` but appends the content to the end of the file instead of truncating it.
+    fn built_in_append_file(&mut self) -> anyhow::Result<()> {
+        let content = self.ins_pop_str()?;
+        let file_name = self.ins_pop_str()?;
+        let path = self.resolve_path(&file_name)?;
+
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .is_ok();
+        self.push_stack(written as i64)?;
+
+        Ok(())
+    }
+
+    /// Pushes the size of a file in bytes and `1`, or `0` and `0` if its metadata cannot be read.
+    fn built_in_file_size(&mut self) -> anyhow::Result<()> {
+        let file_name = self.ins_pop_str()?;
+        let path = self.resolve_path(&file_name)?;
+
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                self.push_stack(metadata.len() as i64)?;
+                self.push_stack(1)?;
+            }
+            Err(_) => {
+                self.push_stack(0)?;
+                self.push_stack(0)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the last modification time of a file as seconds since the unix epoch and `1`, or
+    /// `0` and `0` if it cannot be read.
+    fn built_in_file_modified(&mut self) -> anyhow::Result<()> {
+        let file_name = self.ins_pop_str()?;
+        let path = self.resolve_path(&file_name)?;
+
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        match modified {
+            Some(duration) => {
+                self.push_stack(duration.as_secs() as i64)?;
+                self.push_stack(1)?;
+            }
+            None => {
+                self.push_stack(0)?;
+                self.push_stack(0)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads and returns information about the next file item (dir or file).
+    fn built_in_traverse_dir_next(&mut self) -> anyhow::Result<()> {
+        let dir_iter = match self.get_dynamic_object()?.downcast_mut::<DirIter>() {
+            Some(iter) => iter,
+            None => return Err(anyhow!("invalid dynamic object")),
+        };
+
+        match dir_iter.next() {
+            Some(entry) => {
+                let entry = entry?;
+                let path = entry.path();
+
+                self.ins_push_str(path.to_str().unwrap())?;
+                if path.extension().is_some() {
+                    self.ins_push_str(path.extension().unwrap().to_str().unwrap())?;
+                } else {
+                    self.push_stack(0)?; // No extension
+                }
+                self.push_stack(entry.metadata()?.is_dir() as i64)?;
+                self.push_stack(1)?; // For Some
+            }
+            None => {
+                self.push_stack(0)?; // For None
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a directory path and starts a traverse directory process.
+    /// Returns the id for the directory iterator.
+    fn built_in_traverse_dir(&mut self) -> anyhow::Result<()> {
+        let dir_name = self.ins_pop_str()?;
+        let dir_iter: DirIter = Box::new(fs::read_dir(self.resolve_path(&dir_name)?)?);
+
+        self.add_dynamic_object(Box::new(dir_iter))?;
+
+        Ok(())
+    }
+
+    /// Same as `TRAVERSE_DIR` but it walks into the subdirectories as well.
+    fn built_in_traverse_dir_recursive(&mut self) -> anyhow::Result<()> {
+        let dir_name = self.ins_pop_str()?;
+        let dir_iter: DirIter = Box::new(RecursiveDirIter {
+            work_stack: vec![fs::read_dir(self.resolve_path(&dir_name)?)?],
+        });
+
+        self.add_dynamic_object(Box::new(dir_iter))?;
+
+        Ok(())
+    }
+
+    /// Same as `TRAVERSE_DIR` but the items are sorted by their paths, so that the order doesn't
+    /// depend on the OS.
+    fn built_in_traverse_dir_sorted(&mut self) -> anyhow::Result<()> {
+        let dir_name = self.ins_pop_str()?;
+        let mut entries =
+            fs::read_dir(self.resolve_path(&dir_name)?)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.path());
+
+        let dir_iter: DirIter = Box::new(entries.into_iter().map(Ok));
+        self.add_dynamic_object(Box::new(dir_iter))?;
+
+        Ok(())
+    }
+
+    /// Add an immediate value to the last value on stack
+    fn ins_add_imm(&mut self, number: i64) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        let val = self.arithmetic(
+            val,
+            number,
+            "+",
+            i64::checked_add,
+            i64::wrapping_add,
+            i64::saturating_add,
+        )?;
+        self.push_stack(val)?;
+
+        Ok(())
+    }
+
+    /// Subtract an immediate value from the last value on stack
+    fn ins_sub_imm(&mut self, number: i64) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        let val = self.arithmetic(
+            val,
+            number,
+            "-",
+            i64::checked_sub,
+            i64::wrapping_sub,
+            i64::saturating_sub,
+        )?;
+        self.push_stack(val)?;
+
+        Ok(())
+    }
+
+    /// Decrement the last value on stack
+    fn ins_decr(&mut self) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        let val = self.arithmetic(
+            val,
+            1,
+            "-",
+            i64::checked_sub,
+            i64::wrapping_sub,
+            i64::saturating_sub,
+        )?;
+        self.push_stack(val)?;
+
+        Ok(())
+    }
+
+    /// Increment the last value on stack
+    fn ins_incr(&mut self) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        let val = self.arithmetic(
+            val,
+            1,
+            "+",
+            i64::checked_add,
+            i64::wrapping_add,
+            i64::saturating_add,
+        )?;
+        self.push_stack(val)?;
+
+        Ok(())
+    }
+
+    /// Replace the last value on stack with its sign
+    fn ins_sign(&mut self) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        self.push_stack(val.signum())?;
+
+        Ok(())
+    }
+
+    /// Keep the smaller of the last two values on stack
+    fn ins_min(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        self.push_stack(lhs.min(rhs))?;
+
+        Ok(())
+    }
+
+    /// Keep the larger of the last two values on stack
+    fn ins_max(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        self.push_stack(lhs.max(rhs))?;
+
+        Ok(())
+    }
+
+    /// Replace the last value on stack with `1` if it is nonzero, `0` otherwise
+    fn ins_bool(&mut self) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        self.push_stack((val != 0) as i64)?;
+
+        Ok(())
+    }
+
+    /// Push `1` if both of the last two values on stack are nonzero, `0` otherwise
+    fn ins_and_b(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        self.push_stack((lhs != 0 && rhs != 0) as i64)?;
+
+        Ok(())
+    }
+
+    /// Push `1` if any of the last two values on stack is nonzero, `0` otherwise
+    fn ins_or_b(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        self.push_stack((lhs != 0 || rhs != 0) as i64)?;
+
+        Ok(())
+    }
+
+    /// Halt the program if the last two values on stack are not equal
+    fn ins_assert_eq(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        if lhs != rhs {
+            writeln!(
+                self.writer,
+                "{}assertion failed at instruction {}: {} != {}",
+                self.print_prefix, self.ip, lhs, rhs
+            )?;
+            self.halt = Some(ASSERTION_FAILED_EXIT_CODE);
+        }
+
+        Ok(())
+    }
+
+    /// Replace the last value on stack with its absolute value
+    fn ins_abs(&mut self) -> anyhow::Result<()> {
+        let val = self.pop_stack()?;
+        match val.checked_abs() {
+            Some(abs) => self.push_stack(abs)?,
+            None => return Err(anyhow!("Arithmetic overflow: absolute value of {}", val)),
+        }
+
+        Ok(())
+    }
+
+    /// Compare two strings
+    fn ins_cmp_str(&mut self) -> anyhow::Result<()> {
+        let rhs = self.ins_pop_str()?;
+        let lhs = self.ins_pop_str()?;
+        self.set_cmp_result(lhs.cmp(&rhs))?;
+
+        Ok(())
+    }
+
+    /// Compare two strings ignoring ASCII case
+    fn ins_cmp_str_ci(&mut self) -> anyhow::Result<()> {
+        let rhs = self.ins_pop_str()?;
+        let lhs = self.ins_pop_str()?;
+        let ordering = lhs
+            .bytes()
+            .map(|b| b.to_ascii_lowercase())
+            .cmp(rhs.bytes().map(|b| b.to_ascii_lowercase()));
+        self.set_cmp_result(ordering)?;
+
+        Ok(())
+    }
+
+    /// Stores the result of a comparison according to the `CmpMode`
+    fn set_cmp_result(&mut self, ordering: Ordering) -> anyhow::Result<()> {
+        match self.cmp_mode {
+            CmpMode::Stack => self.push_stack(ordering as i64)?,
+            CmpMode::Flags => self.cmp_flag = Some(ordering),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the result of the last comparison as `-1`, `0` or `1` according to the `CmpMode`
+    fn cmp_result(&mut self) -> anyhow::Result<i64> {
+        match self.cmp_mode {
+            CmpMode::Stack => self.pop_stack(),
+            CmpMode::Flags => match self.cmp_flag {
+                Some(ordering) => Ok(ordering as i64),
+                None => Err(anyhow!("Conditional jump without a previous comparison.")),
+            },
+        }
+    }
+
+    /// Compare two numbers or two strings, depending on the kinds of the values on stack
+    fn ins_cmp3(&mut self) -> anyhow::Result<()> {
+        if self.sp < 1 {
+            return Err(anyhow!("Fatal: stack is smaller than 2"));
+        }
+
+        let rhs_kind = self.tags[self.sp as usize];
+        let lhs_top = match rhs_kind {
+            Param::Number => self.sp - 1,
+            Param::Str => self.sp - self.str_cells(self.stack[self.sp as usize]),
+        };
+        if lhs_top < 0 {
+            return Err(anyhow!("Fatal: stack is smaller than 2"));
+        }
+
+        let lhs_kind = self.tags[lhs_top as usize];
+        if lhs_kind != rhs_kind {
+            return Err(anyhow!(
+                "CMP3 cannot compare {} with {}",
+                lhs_kind.describe(),
+                rhs_kind.describe()
+            ));
+        }
+
+        match rhs_kind {
+            Param::Number => self.ins_cmp(),
+            Param::Str => self.ins_cmp_str(),
+        }
+    }
+
+    /// Compare two numbers
+    fn ins_cmp(&mut self) -> anyhow::Result<()> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        self.set_cmp_result(lhs.cmp(&rhs))?;
+
+        Ok(())
+    }
+
+    /// Jump if two numbers are equal
+    fn ins_je(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? != 0 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if two numbers are not equal
+    fn ins_jne(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? == 0 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the first number is greater
+    fn ins_jg(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? != 1 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the first number is less
+    fn ins_jl(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? != -1 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the first number is greater or equal
+    fn ins_jge(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? == -1 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the first number is less or equal
+    fn ins_jle(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.cmp_result()? == 1 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the last value on stack equals `imm`
+    fn ins_jeq_imm(&mut self, imm: i64, count: i32) -> anyhow::Result<()> {
+        if self.pop_stack()? != imm {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the value on top of the stack is zero
+    fn ins_jz(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.pop_stack()? != 0 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if the value on top of the stack is not zero
+    fn ins_jnz(&mut self, count: i32) -> anyhow::Result<()> {
+        if self.pop_stack()? == 0 {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if two strings are equal
+    fn ins_je_str(&mut self, count: i32) -> anyhow::Result<()> {
+        let rhs = self.ins_pop_str()?;
+        let lhs = self.ins_pop_str()?;
+        if lhs != rhs {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Jump if two strings are not equal
+    fn ins_jne_str(&mut self, count: i32) -> anyhow::Result<()> {
+        let rhs = self.ins_pop_str()?;
+        let lhs = self.ins_pop_str()?;
+        if lhs == rhs {
+            return Ok(());
+        }
+
+        self.ins_jmp(count)
+    }
+
+    /// Pop `count` values from stack
+    fn ins_drop_n(&mut self, count: i32) -> anyhow::Result<()> {
+        if count < 0 {
+            return Err(anyhow!(
+                "DROP_N expects a non-negative count, got {}",
+                count
+            ));
+        }
+        if self.sp - (count as isize) < -1 {
+            return Err(anyhow!(
+                "DROP_N {} but the stack has only {} values.",
+                count,
+                self.sp + 1
+            ));
+        }
+
+        self.sp -= count as isize;
+
+        Ok(())
+    }
+
+    /// Start a try region whose handler is `count` instructions away
+    fn ins_try_begin(&mut self, count: i32) -> anyhow::Result<()> {
+        let handler = self.ip as i64 - count as i64;
+        if handler < 0 || handler as usize >= self.bytecode.instructions.len() {
+            return Err(anyhow!("Invalid try handler."));
+        }
+
+        self.try_handlers.push(TryHandler {
+            handler: handler as usize,
+            sp: self.sp,
+            frame_depth: self.frame_stack.len(),
+        });
+
+        Ok(())
+    }
+
+    /// End the innermost try region
+    fn ins_try_end(&mut self) -> anyhow::Result<()> {
+        match self.try_handlers.pop() {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("TRY_END without a TRY_BEGIN")),
+        }
+    }
+
+    /// Jump to the case of the selector on stack, or to the default if there is no such case
+    fn ins_switch(&mut self, offsets: &[i32]) -> anyhow::Result<()> {
+        let selector = self.pop_stack()?;
+        let (default, cases) = match offsets.split_first() {
+            Some(split) => split,
+            None => return Err(anyhow!("SWITCH without a default offset")),
+        };
+
+        let offset = usize::try_from(selector)
+            .ok()
+            .and_then(|selector| cases.get(selector))
+            .unwrap_or(default);
+
+        self.ins_jmp(*offset)
+    }
+
+    /// Jump to a location
+    fn ins_jmp(&mut self, count: i32) -> anyhow::Result<()> {
+        if count > self.ip as i32 {
+            return Err(anyhow!("Invalid jump."));
+        }
+
+        let new_ip = ((self.ip as i32) - count) as usize;
+        if new_ip >= self.bytecode.instructions.len() {
+            Err(anyhow!("Invalid jump."))
+        } else {
+            self.ip = new_ip;
+            Ok(())
+        }
+    }
+
+    /// Pop a value from stack and write it to variables of the current frame
+    fn ins_write_var(&mut self, var_name: &str) -> anyhow::Result<()> {
+        let value = self.pop_stack()?;
+        let local_vars = &mut self.current_frame_mut()?.local_vars;
+
+        if let Some(old_value) = local_vars.get_mut(var_name) {
+            *old_value = value;
+        } else {
+            local_vars.insert(var_name.to_string(), value);
+        }
+
+        Ok(())
+    }
+
+    /// Pop a value from stack and save it to a variable in the frame of the caller
+    fn ins_write_upvar(&mut self, var_name: &str) -> anyhow::Result<()> {
+        let value = self.pop_stack()?;
+        self.caller_frame_mut()?
+            .local_vars
+            .insert(var_name.to_string(), value);
+
+        Ok(())
+    }
+
+    /// Load a variable from the frame of the caller to stack
+    fn ins_read_upvar(&mut self, var_name: &str) -> anyhow::Result<()> {
+        match self.caller_frame_mut()?.local_vars.get(var_name) {
+            Some(&var) => {
+                self.push_stack(var)?;
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "Variable '{}' does not exist in the caller frame.",
+                var_name
+            )),
+        }
+    }
+
+    /// Save the value on top of stack to a variable and keep it on stack
+    fn ins_tee_var(&mut self, var_name: &str) -> anyhow::Result<()> {
+        let value = self.pop_stack()?;
+        self.push_stack(value)?;
+        self.push_stack(value)?;
+
+        self.ins_write_var(var_name)
+    }
+
+    /// Load a variable from frame to stack
+    fn ins_read_var(&mut self, var_name: &str) -> anyhow::Result<()> {
+        match self.current_frame()?.local_vars.get(var_name) {
+            Some(&var) => {
+                self.push_stack(var)?;
+                Ok(())
+            }
+            None if self.undefined_var_is_zero => {
+                self.push_stack(0)?;
+                Ok(())
+            }
+            None => Err(anyhow!("Variable '{}' does not exist.", var_name)),
+        }
+    }
+
+    /// Load a variable from frame to stack, or `default` if the variable does not exist
+    fn ins_read_var_or(&mut self, var_name: &str, default: i64) -> anyhow::Result<()> {
+        let value = self
+            .current_frame()?
+            .local_vars
+            .get(var_name)
+            .copied()
+            .unwrap_or(default);
+        self.push_stack(value)?;
+
+        Ok(())
+    }
+
+    /// Exchange the values of two variables of the current frame
+    fn ins_swap_var(&mut self, lhs: &str, rhs: &str) -> anyhow::Result<()> {
+        let local_vars = &mut self.current_frame_mut()?.local_vars;
+        let (lhs_value, rhs_value) = match (local_vars.get(lhs), local_vars.get(rhs)) {
+            (Some(&lhs_value), Some(&rhs_value)) => (lhs_value, rhs_value),
+            (None, _) => return Err(anyhow!("Variable '{}' does not exist.", lhs)),
+            (_, None) => return Err(anyhow!("Variable '{}' does not exist.", rhs)),
+        };
+
+        local_vars.insert(lhs.to_string(), rhs_value);
+        local_vars.insert(rhs.to_string(), lhs_value);
+
+        Ok(())
+    }
+
+    /// Pop a string from stack and write it to string variables of the current frame
+    fn ins_write_str_var(&mut self, var_name: &str) -> anyhow::Result<()> {
+        let value = self.ins_pop_str()?;
+        self.current_frame_mut()?
+            .local_strs
+            .insert(var_name.to_string(), value);
+
+        Ok(())
+    }
+
+    /// Load a string variable from frame to stack
+    fn ins_read_str_var(&mut self, var_name: &str) -> anyhow::Result<()> {
+        match self.current_frame()?.local_strs.get(var_name) {
+            Some(value) => {
+                let value = value.clone();
+                self.ins_push_str(&value)
+            }
+            None => Err(anyhow!("String variable '{}' does not exist.", var_name)),
+        }
+    }
+
+    // Call a function
+    fn ins_call(&mut self, fn_name: &str) -> anyhow::Result<()> {
+        // Host functions are taken out while they run, since they need `self` mutably as well
+        if let Some(mut host_fn) = self.host_fns.remove(fn_name) {
+            let result = host_fn(self);
+            self.host_fns.insert(fn_name.to_string(), host_fn);
+            return result;
+        }
+
+        // See if there is a builtin function
+        if let Some(builtin) = Self::builtin_fn(fn_name) {
+            if !self.capabilities.contains(&builtin.capability) {
+                return Err(anyhow!("capability '{}' not enabled", builtin.capability));
+            }
+            self.check_params(fn_name, builtin.params)?;
+            return (builtin.func)(self);
+        }
+
+        let fn_addr = match self.bytecode.fn_table.get(fn_name) {
+            Some(func) => func.ptr,
+            None => return Err(anyhow!("Function '{}' does not exist.", fn_name)),
+        };
+
+        self.enter_function(fn_addr)?;
+        if fn_name == ENTRY_POINT {
+            self.current_frame_mut()?.local_vars = std::mem::take(&mut self.inputs);
+        }
+
+        Ok(())
+    }
+
+    /// Call the function that is `count` instructions away
+    fn ins_call_rel(&mut self, count: i32) -> anyhow::Result<()> {
+        let fn_addr = self.ip as i64 - count as i64;
+        // A call to itself would continue with the next instruction, since the ip does not change
+        if count == 0 || fn_addr < 0 || fn_addr as usize >= self.bytecode.instructions.len() {
+            return Err(anyhow!("Invalid call."));
+        }
+
+        self.enter_function(fn_addr as usize)
+    }
+
+    /// Creates the frame of a function that starts at `fn_addr` and jumps to it. The function
+    /// returns to the instruction after the current one.
+    fn enter_function(&mut self, fn_addr: usize) -> anyhow::Result<()> {
+        if self.frame_stack.len() >= self.max_call_depth {
+            return Err(anyhow!("maximum recursion depth exceeded"));
+        }
+
+        // ip + 1: not to call a function forever
+        self.frame_stack.push(StackFrame::new(self.ip + 1));
+        self.fp += 1;
+        self.ip = fn_addr;
+        Ok(())
+    }
+
+    /// Return from the function by saving the return value
+    fn ins_ret_value(&mut self) -> anyhow::Result<()> {
+        match self.frame_stack.pop() {
+            Some(mut stack_frame) => {
+                stack_frame.ret_value = Some(self.pop_stack()?);
+                self.drop_try_regions();
+                self.ip = stack_frame.ret_addr;
+                self.push_stack(stack_frame.ret_value.unwrap())?;
+                self.fp -= 1;
+                self.last_ret_value = stack_frame.ret_value;
+                Ok(())
+            }
+            None => Err(InternalError("Fatal: unexpected return").into()),
+        }
+    }
+
+    /// Return from the function with the last `count` values on stack
+    fn ins_ret_values(&mut self, count: i32) -> anyhow::Result<()> {
+        if count < 0 || self.sp + 1 < count as isize {
+            return Err(anyhow!(
+                "RETURN_VALUES {} but the stack has {} values.",
+                count,
+                self.sp + 1
+            ));
+        }
+
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            values.push(self.pop_stack()?);
+        }
+
+        self.ins_ret()?;
+
+        // Values are popped in reverse, so push them back starting from the last one
+        for value in values.into_iter().rev() {
+            self.push_stack(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return from the function
+    fn ins_ret(&mut self) -> anyhow::Result<()> {
+        match self.frame_stack.pop() {
+            Some(stack_frame) => {
+                self.drop_try_regions();
+                self.ip = stack_frame.ret_addr;
+                self.fp -= 1;
+                self.last_ret_value = None;
+                Ok(())
+            }
+            None => Err(InternalError("Fatal: unexpected return").into()),
+        }
+    }
+
+    /// Drops the try regions that were left open by the functions that have returned, so that they
+    /// do not catch the errors of a later function at the same depth
+    fn drop_try_regions(&mut self) {
+        let depth = self.frame_stack.len();
+        while matches!(self.try_handlers.last(), Some(handler) if handler.frame_depth > depth) {
+            self.try_handlers.pop();
+        }
+    }
+
+    /// Push the value of the last `RETURN_VALUE` and `1`, or only `0` if the last function returned
+    /// without a value
+    fn ins_load_ret(&mut self) -> anyhow::Result<()> {
+        match self.last_ret_value {
+            Some(value) => {
+                self.push_stack(value)?;
+                self.push_stack(1)?;
+            }
+            None => self.push_stack(0)?,
+        }
+
+        Ok(())
+    }
+
+    /// Halt with the return value of `MAIN` as the exit code, or `0` if it returned without a
+    /// value
+    fn ins_exit(&mut self) -> anyhow::Result<()> {
+        let exit_code = match self.last_ret_value {
+            Some(value) => {
+                i32::try_from(value).map_err(|_| anyhow!("Exit code {} is out of range.", value))?
+            }
+            None => 0,
+        };
+        self.halt = Some(exit_code);
+
+        Ok(())
+    }
+
+    /// Push a copy of a string of the constant pool to stack
+    fn ins_load_const_str(&mut self, index: usize) -> anyhow::Result<()> {
+        // The pool is moved out while the string is copied, since pushing borrows the VM mutably
+        let str_pool = std::mem::take(&mut self.bytecode.str_pool);
+        let result = match str_pool.get(index) {
+            Some(s) => self.ins_push_str(s),
+            None => Err(anyhow!("String constant {} does not exist.", index)),
+        };
+        self.bytecode.str_pool = str_pool;
+
+        result
+    }
+
+    /// Push a copy of the value at `index`, counting from the top if it is non-negative and from
+    /// the bottom otherwise
+    fn ins_pick(&mut self, index: i32) -> anyhow::Result<()> {
+        let size = self.sp + 1;
+        let cell = if index >= 0 {
+            self.sp - index as isize
+        } else {
+            -(index as isize) - 1
+        };
+
+        if cell < 0 || cell >= size {
+            return Err(anyhow!(
+                "PICK {} but the stack has only {} values.",
+                index,
+                size
+            ));
+        }
+
+        self.push_stack(self.stack[cell as usize])?;
+        Ok(())
+    }
+
+    /// Push a number to stack
+    fn ins_load_val(&mut self, number: i64) -> anyhow::Result<()> {
+        self.push_stack(number)?;
+        Ok(())
+    }
+
+    /// Add two numbers
+    fn ins_add(&mut self) -> anyhow::Result<()> {
+        if self.sp < 1 {
+            return Err(anyhow!("Fatal: stack is smaller than 2"));
+        }
+
+        let lhs = self.pop_stack()?;
+        let rhs = self.pop_stack()?;
+
+        let result = self.arithmetic(
+            lhs,
+            rhs,
+            "+",
+            i64::checked_add,
+            i64::wrapping_add,
+            i64::saturating_add,
+        )?;
+        self.push_stack(result)?;
+
+        Ok(())
+    }
+
+    /// Subtract two numbers
+    fn ins_sub(&mut self) -> anyhow::Result<()> {
+        if self.sp < 1 {
+            return Err(anyhow!("Fatal: stack is smaller than 2"));
+        }
+
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+
+        let result = self.arithmetic(
+            lhs,
+            rhs,
+            "-",
+            i64::checked_sub,
+            i64::wrapping_sub,
+            i64::saturating_sub,
+        )?;
+        self.push_stack(result)?;
+
+        Ok(())
+    }
+
+    /// Multiply two numbers
+    fn ins_mul(&mut self) -> anyhow::Result<()> {
+        if self.sp < 1 {
+            return Err(anyhow!("Fatal: stack size is smaller than 2"));
+        }
+
+        let lhs = self.pop_stack()?;
+        let rhs = self.pop_stack()?;
+
+        let result = self.arithmetic(
+            lhs,
+            rhs,
+            "*",
+            i64::checked_mul,
+            i64::wrapping_mul,
+            i64::saturating_mul,
+        )?;
+        self.push_stack(result)?;
+
+        Ok(())
+    }
+
+    /// Raise the base to the power of the exponent where the exponent is the last value on stack
+    fn ins_pow(&mut self) -> anyhow::Result<()> {
+        let exp = self.pop_stack()?;
+        let base = self.pop_stack()?;
+
+        if exp < 0 {
+            return Err(anyhow!("Negative exponent: {}", exp));
+        }
+
+        // An exponent that does not fit in `u32` is reported as an overflow instead of truncated
+        let result = u32::try_from(exp)
+            .ok()
+            .and_then(|exp| base.checked_pow(exp));
+        match result {
+            Some(result) => self.push_stack(result)?,
+            None => return Err(anyhow!("Arithmetic overflow: {} ^ {}", base, exp)),
+        }
+
+        Ok(())
+    }
+
+    /// Apply one of the given operations on `lhs` and `rhs` depending on the arithmetic mode
+    fn arithmetic(
+        &self,
+        lhs: i64,
+        rhs: i64,
+        op: &str,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+        saturating: fn(i64, i64) -> i64,
+    ) -> anyhow::Result<i64> {
+        match self.arithmetic_mode {
+            ArithmeticMode::Wrapping => Ok(wrapping(lhs, rhs)),
+            ArithmeticMode::Saturating => Ok(saturating(lhs, rhs)),
+            ArithmeticMode::Checked => checked(lhs, rhs)
+                .ok_or_else(|| anyhow!("Arithmetic overflow: {} {} {}", lhs, op, rhs)),
+        }
+    }
+
+    /// Push a string on stack
+    ///
+    /// To use the least amount of memory, instead of putting 1-byte characters to per memory
+    /// cell, it puts 8 character to a memory cell. We can also take advantage of cheap (but dangerous)
+    /// copies like this. Because we just map the byte array to the stack as is.
+    ///
+    /// Eg.
+    /// Suppose that we have 4, 8-byte wide memory cells from bottom to top respectively.
+    /// |   0   |   0   |   0   |   0   |
+    /// PUSH_STR 'hello world!' puts the data and the size of the string.
+    /// |  h e l l o _ w o  |  r l d !  |  12  |
+    /// With string canaries, `STR_CANARY` is put below the data, where the size says the string
+    /// starts.
+    /// |  STR_CANARY  |  h e l l o _ w o  |  r l d !  |  12  |
+    fn ins_push_str(&mut self, s: &str) -> anyhow::Result<()> {
+        let str_len = s.len() as i64;
+        let data_cells = str_data_cells(str_len);
+        let prev_sp = self.sp;
+
+        // Data cells, the canary and the length cell should fit in the stack
+        if self.sp + self.str_cells(str_len) >= self.stack.len() as isize {
+            return Err(anyhow!("fatal: out of memory"));
+        }
+
+        if self.string_canaries {
+            self.push_stack(STR_CANARY)?;
+        }
+
+        // Zero the data cells first, so the unused bytes of the last cell are not left as garbage
+        let start = (self.sp + 1) as usize;
+        self.stack[start..start + data_cells as usize].fill(0);
+
+        // Copy the string to the stack as is
+        let src = s.as_ptr();
+        unsafe {
+            let dest = self.stack.as_mut_ptr().add(start);
+            std::ptr::copy_nonoverlapping(src, dest as *mut u8, s.len());
+        }
+
+        self.sp += data_cells;
+
+        // Finally the string length
+        self.push_stack(str_len)?;
+        self.tags[self.sp as usize] = Param::Str;
+
+        // Read the string back the way `ins_pop_str` does. It should get the same bytes and pop
+        // exactly the cells that are pushed here.
+        let agrees = self
+            .peek_str_bytes()
+            .is_ok_and(|bytes| bytes == s.as_bytes())
+            && self.str_cells(str_len) == self.sp - prev_sp;
+        debug_assert!(agrees, "pushed string does not match the popped one");
+        if !agrees {
+            return Err(InternalError("fatal: pushed string does not match the popped one").into());
+        }
+
+        Ok(())
+    }
+
+    /// Number of memory cells that a string with length `str_len` occupies on stack, including the
+    /// length cell and the canary if there is one.
+    fn str_cells(&self, str_len: i64) -> isize {
+        str_data_cells(str_len) + 1 + self.string_canaries as isize
+    }
+
+    /// Pops a string from stack. Discards the poped string. This is mainly for internal use.
+    fn ins_pop_str(&mut self) -> anyhow::Result<String> {
+        let s = self.peek_str()?;
+        self.sp -= self.str_cells(s.len() as i64);
+
+        Ok(s)
+    }
+
+    /// Reads the string on top of stack without popping it
+    fn peek_str(&self) -> anyhow::Result<String> {
+        String::from_utf8(self.peek_str_bytes()?.to_vec())
+            .map_err(|_| anyhow!("fatal: string on stack is not valid UTF-8."))
+    }
+
+    /// Returns the raw bytes of the string on top of stack
+    fn peek_str_bytes(&self) -> anyhow::Result<&[u8]> {
+        if self.sp < 0 {
+            return Err(anyhow!("Fatal: stack is empty."));
+        }
+
+        let str_len = self.stack[self.sp as usize];
+        if str_len < 0 {
+            return Err(anyhow!("fatal: negative strlen."));
+        }
+
+        // Data cells are right below the length cell, and the canary is below them
+        let start = self.sp - str_data_cells(str_len);
+        if start - (self.string_canaries as isize) < 0 {
+            return Err(anyhow!("fatal: not enough stack."));
+        }
+
+        // A length that does not match the pushed one points to another cell than the canary
+        if self.string_canaries && self.stack[start as usize - 1] != STR_CANARY {
+            return Err(anyhow!("fatal: string canary is corrupted."));
+        }
+
+        // Read the raw bytes, since a character may be encoded in multiple bytes
+        let str_ptr = self.stack.as_ptr() as *const u8;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(str_ptr.offset(start * CELL_SIZE as isize), str_len as usize)
+        };
+
+        Ok(bytes)
+    }
+
+    fn pop_stack(&mut self) -> anyhow::Result<i64> {
+        if self.sp < 0 {
+            return Err(anyhow!("Fatal: stack is empty."));
+        }
+
+        self.sp -= 1;
+        Ok(self.stack[(self.sp + 1) as usize])
+    }
+
+    fn push_stack(&mut self, data: i64) -> anyhow::Result<()> {
+        if self.sp + 1 >= self.stack.len() as isize {
+            return Err(anyhow!("Fatal: stack is full."));
+        }
+
+        self.sp += 1;
+        self.stack[self.sp as usize] = data;
+        self.tags[self.sp as usize] = Param::Number;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Instruction;
+    use std::{cell::RefCell, mem::discriminant, rc::Rc};
+
+    use super::*;
+
+    /// Writer that shares its buffer, so the output can be inspected after it is given to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Directory of a test under the temporary directory. The name includes the process id, so
+    /// concurrent test runs do not share files. It is removed with everything in it when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("bci_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn join(&self, path: &str) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Runs the program to completion and returns the VM along with its output.
+    fn run_with_output(program: &str) -> (BciVm, String) {
+        let buf = SharedBuf::default();
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_writer(Box::new(buf.clone()));
+        vm.run().unwrap();
+        (vm, buf.contents())
+    }
+
+    fn run_until_instruction(program: &str, instruction: Instruction) -> anyhow::Result<BciVm> {
+        let mut vm = BciVm::load(program).unwrap();
+        loop {
+            vm.next_instruction().unwrap();
+            if discriminant(&vm.bytecode.instructions[vm.ip]) == discriminant(&instruction) {
+                vm.next_instruction().unwrap();
+                return Ok(vm);
+            } else if vm.halt.is_some() {
+                return Err(anyhow!("process is halted unexpectedly"));
+            }
+        }
+    }
+
+    #[test]
+    fn load() {
+        let program = "MAIN:\nLOAD_VAL 10\nLOAD_VAL 20\nHALT 0";
+        let vm = run_until_instruction(program, Instruction::Halt(0)).unwrap();
+
+        let stack = [10, 20];
+        assert_eq!(&vm.stack[0..2], &stack);
+        assert_eq!(vm.sp, 1);
+    }
+
+    #[test]
+    fn read_write() {
+        let program = "MAIN:\nLOAD_VAL 10\nWRITE_VAR 'x'\nLOAD_VAL 20\nREAD_VAR 'x'\n";
+        let vm = run_until_instruction(program, Instruction::ReadVar("".into())).unwrap();
+
+        assert_eq!(vm.stack[vm.sp as usize], 10);
+        assert_eq!(
+            vm.frame_stack[vm.fp as usize].local_vars.get("x"),
+            Some(&10)
+        );
+        assert_eq!(vm.sp, 1);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let base_program = "MAIN:\nLOAD_VAL 6\nLOAD_VAL 4\n";
+
+        let add_prog = base_program.to_string() + "ADD";
+        let vm = run_until_instruction(&add_prog, Instruction::Add).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 10);
+
+        let mul_prog = base_program.to_string() + "MUL";
+        let vm = run_until_instruction(&mul_prog, Instruction::Mul).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 24);
+    }
+
+    #[test]
+    fn sub() {
+        let program = "MAIN:\nLOAD_VAL 6\nLOAD_VAL 4\nSUB";
+        let vm = run_until_instruction(program, Instruction::Sub).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 2);
+    }
+
+    #[test]
+    fn arithmetic_modes() {
+        fn run(body: &str, mode: ArithmeticMode) -> anyhow::Result<i64> {
+            let program = format!("MAIN:\n{}\nHALT 0", body);
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.set_arithmetic_mode(mode);
+            vm.run()?;
+            Ok(vm.stack[vm.sp as usize])
+        }
+
+        let max = format!("LOAD_VAL {}", i64::MAX);
+        let min = format!("LOAD_VAL {}", i64::MIN);
+        let add = format!("{}\nLOAD_VAL 1\nADD", max);
+        let sub = format!("{}\nLOAD_VAL 1\nSUB", min);
+        let mul = format!("{}\nLOAD_VAL 2\nMUL", max);
+        let incr = format!("{}\nINCR", max);
+        let decr = format!("{}\nDECR", min);
+
+        use ArithmeticMode::*;
+        assert_eq!(run(&add, Wrapping).unwrap(), i64::MIN);
+        assert_eq!(run(&sub, Wrapping).unwrap(), i64::MAX);
+        assert_eq!(run(&mul, Wrapping).unwrap(), -2);
+        assert_eq!(run(&incr, Wrapping).unwrap(), i64::MIN);
+        assert_eq!(run(&decr, Wrapping).unwrap(), i64::MAX);
+
+        assert_eq!(run(&add, Saturating).unwrap(), i64::MAX);
+        assert_eq!(run(&sub, Saturating).unwrap(), i64::MIN);
+        assert_eq!(run(&mul, Saturating).unwrap(), i64::MAX);
+        assert_eq!(run(&incr, Saturating).unwrap(), i64::MAX);
+        assert_eq!(run(&decr, Saturating).unwrap(), i64::MIN);
+
+        for body in [&add, &sub, &mul, &incr, &decr] {
+            assert!(run(body, Checked).is_err());
+        }
+    }
+
+    #[test]
+    fn incr_decr_overflow() {
+        let program = format!("MAIN:\nLOAD_VAL {}\nINCR\nHALT 0", i64::MAX);
+        let mut vm = BciVm::load(&program).unwrap();
+        assert_eq!(
+            vm.run().unwrap_err().to_string(),
+            format!("line 3: Arithmetic overflow: {} + 1", i64::MAX)
+        );
+
+        let program = format!("MAIN:\nLOAD_VAL {}\nDECR\nHALT 0", i64::MIN);
+        let mut vm = BciVm::load(&program).unwrap();
+        assert_eq!(
+            vm.run().unwrap_err().to_string(),
+            format!("line 3: Arithmetic overflow: {} - 1", i64::MIN)
+        );
+    }
+
+    #[test]
+    fn jmp() {
+        let program = "MAIN:\nNOP\nLOAD_VAL 1\nJMP 2";
+        let vm = run_until_instruction(program, Instruction::Jmp(0)).unwrap();
+        assert_eq!(vm.bytecode.instructions[vm.ip], Instruction::Nop);
+
+        let program = "MAIN:\nJMP -2\nLOAD_VAL 1\nNOP";
+        let vm = run_until_instruction(program, Instruction::Jmp(0)).unwrap();
+        assert_eq!(vm.bytecode.instructions[vm.ip], Instruction::Nop);
+    }
+
+    #[test]
+    fn push_str() {
+        let inp_str = "hello world";
+        let program = format!("MAIN:\nPUSH_STR '{}'\nHALT 0", inp_str);
+
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+
+        // Check if string contents are equal
+        let stack_ptr = vm.stack.as_ptr() as *const u8;
+        for i in 0..inp_str.len() {
+            unsafe {
+                assert_eq!(inp_str.as_bytes()[i], *stack_ptr.add(i));
+            }
+        }
+
+        // Check the size
+        assert_eq!(
+            vm.stack[inp_str.len() / CELL_SIZE + 1] as usize,
+            inp_str.len()
+        );
+    }
+
+    #[test]
+    fn pop_str() {
+        let program = "MAIN:\nPUSH_STR 'hello world'\nPOP_STR\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        // Stack should be empty
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn str_cell_accounting() {
+        for len in 0..=16 {
+            let s = "x".repeat(len);
+            let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+            vm.ins_push_str(&s).unwrap();
+            // Data cells and the length cell
+            assert_eq!(vm.sp + 1, str_data_cells(len as i64) + 1);
+
+            assert_eq!(vm.ins_pop_str().unwrap(), s);
+            assert_eq!(vm.sp, -1);
+        }
+    }
+
+    #[test]
+    fn str_cell_accounting_of_long_strings() {
+        for s in [
+            "x".repeat(63),
+            "x".repeat(64),
+            "x".repeat(65),
+            "x".repeat(1000),
+            "é".repeat(100),
+        ] {
+            let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+            // Values below the string should not be affected
+            vm.push_stack(42).unwrap();
+            vm.ins_push_str(&s).unwrap();
+            assert_eq!(vm.sp, str_data_cells(s.len() as i64) + 1);
+
+            assert_eq!(vm.ins_pop_str().unwrap(), s);
+            assert_eq!(vm.pop_stack().unwrap(), 42);
+            assert_eq!(vm.sp, -1);
+        }
+
+        // Strings that do not fit are rejected before anything is written
+        let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+        let s = "x".repeat(vm.stack.len() * CELL_SIZE);
+        assert!(vm.ins_push_str(&s).is_err());
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn push_str_zero_fills_last_cell() {
+        for len in [3, 4, 5, 7, 8, 9, 15, 16] {
+            let s = "x".repeat(len);
+            let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+            // Leave garbage on the stack
+            vm.stack.fill(-1);
+            vm.ins_push_str(&s).unwrap();
+
+            let data_cells = str_data_cells(len as i64) as usize;
+            let bytes = unsafe {
+                std::slice::from_raw_parts(vm.stack.as_ptr() as *const u8, data_cells * CELL_SIZE)
+            };
+            assert_eq!(&bytes[..len], s.as_bytes());
+            assert!(bytes[len..].iter().all(|&byte| byte == 0));
+            assert_eq!(vm.stack[data_cells], len as i64);
+        }
+    }
+
+    #[test]
+    fn pop_str_utf8() {
+        let (_, output) = run_with_output("MAIN:\nPUSH_STR 'héllo 世界'\nCALL PRINT_STR\nHALT 0");
+        assert_eq!(output, ">>>>> héllo 世界\n");
+
+        // Cut the length of the string in the middle of 'é', which is 2 bytes
+        let program = "MAIN:\nPUSH_STR 'hé'\nPOP_STR\nHALT 0";
+        let mut vm = run_until_instruction(program, Instruction::PushStr("".into())).unwrap();
+        vm.stack[vm.sp as usize] = 2;
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn cmp() {
+        let program = "MAIN:\nLOAD_VAL 1\nLOAD_VAL 1\nCMP";
+        let vm = run_until_instruction(program, Instruction::Cmp).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 0);
+        assert_eq!(vm.sp, 0);
+    }
+
+    #[test]
+    fn cmp_str() {
+        let program = "MAIN:\nPUSH_STR 'hello'\nPUSH_STR 'hello'\nCMP_STR";
+        let vm = run_until_instruction(program, Instruction::CmpStr).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 0);
+        assert_eq!(vm.sp, 0);
+    }
+
+    #[test]
+    fn cmp_str_ci() {
+        let cmp = |lhs: &str, rhs: &str| {
+            let program = format!(
+                "MAIN:\nPUSH_STR '{}'\nPUSH_STR '{}'\nCMP_STR_CI\nHALT 0",
+                lhs, rhs
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.sp, 0);
+            vm.pop_stack().unwrap()
+        };
+
+        assert_eq!(cmp("TXT", "txt"), 0);
+        assert_eq!(cmp("File.Txt", "file.TXT"), 0);
+        assert_eq!(cmp("abc", "ABD"), -1);
+        assert_eq!(cmp("B", "a"), 1);
+        assert_eq!(cmp("txt", "txts"), -1);
+        // Only ASCII letters are folded
+        assert_eq!(cmp("É", "é"), -1);
+    }
+
+    #[test]
+    fn factorial() {
+        let program = include_str!("../examples/factorial.bci").replace("CALL PRINT", "NOP");
+        let mut vm = BciVm::load(&program).unwrap();
+        let instructions = vm.bytecode.instructions.clone();
+        vm.run().unwrap();
+
+        assert_eq!(vm.halt, Some(0));
+        assert_eq!(
+            vm.frame_stack[vm.fp as usize].local_vars.get("result"),
+            Some(&720)
+        );
+        // Reading instructions by value must leave the bytecode intact
+        assert_eq!(vm.bytecode.instructions, instructions);
+    }
+
+    #[test]
+    fn trace() {
+        let program = "MAIN:\nLOAD_VAL 2\nINCR\nCALL PRINT\nHALT 0";
+        let buf = SharedBuf::default();
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_writer(Box::new(buf.clone()));
+        vm.set_trace(true);
+        vm.run().unwrap();
+
+        let expected = [
+            "[trace] 0000: Call(\"MAIN\") (top: empty)",
+            "[trace] 0002: Nop (top: empty)",
+            "[trace] 0003: LoadVal(2) (top: empty)",
+            "[trace] 0004: Incr (top: 2)",
+            "[trace] 0005: Call(\"PRINT\") (top: 3)",
+            ">>>>> 3",
+            "[trace] 0006: Halt(0) (top: empty)",
+        ];
+        assert_eq!(buf.contents().lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn print() {
+        let program = "MAIN:\nLOAD_VAL 42\nCALL PRINT\nPUSH_STR 'hello'\nCALL PRINT_STR\nHALT 0";
+        let (_, output) = run_with_output(program);
+        assert_eq!(output, ">>>>> 42\n>>>>> hello\n");
+    }
+
+    #[test]
+    fn optimize() {
+        let program = r"
+            SQUARE:
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            READ_VAR 'x'
+            MUL
+            RETURN_VALUE
+
+            MAIN:
+            LOAD_VAL 0
+            WRITE_VAR 'sum'
+            LOAD_VAL 5
+            WRITE_VAR 'i'
+
+            READ_VAR 'i'
+            LOAD_VAL 0
+            CMP
+            JE -10
+            READ_VAR 'i'
+            CALL SQUARE
+            READ_VAR 'sum'
+            ADD
+            WRITE_VAR 'sum'
+            READ_VAR 'i'
+            DECR
+            WRITE_VAR 'i'
+            JMP 12
+
+            READ_VAR 'sum'
+            HALT 3
+        ";
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        let bytecode = Parser::new(program).parse().unwrap().optimize();
+        assert!(!bytecode.instructions.contains(&Instruction::Nop));
+        let mut optimized_vm = BciVm::from_bytecode(bytecode);
+        optimized_vm.run().unwrap();
+
+        assert_eq!(vm.halt, Some(3));
+        assert_eq!(optimized_vm.halt, vm.halt);
+        assert_eq!(vm.stack[vm.sp as usize], 55);
+        assert_eq!(optimized_vm.stack[optimized_vm.sp as usize], 55);
+        assert_eq!(optimized_vm.sp, vm.sp);
+    }
+
+    #[test]
+    fn write_file() {
+        let dir = TestDir::new("write_file");
+        let path = dir.join("file.txt");
+        let path = path.to_str().unwrap();
+        let program = format!(
+            "MAIN:\nPUSH_STR '{path}'\nPUSH_STR 'hello'\nCALL WRITE_FILE\nWRITE_VAR 'w'\n\
+             PUSH_STR '{path}'\nPUSH_STR ' world'\nCALL APPEND_FILE\nWRITE_VAR 'a'\n\
+             PUSH_STR '{path}'\nCALL READ_FILE\nCALL READ_FILE_NEXT\nHALT 0"
+        );
+
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+
+        let local_vars = &vm.frame_stack[vm.fp as usize].local_vars;
+        assert_eq!(local_vars.get("w"), Some(&1));
+        assert_eq!(local_vars.get("a"), Some(&1));
+
+        // The line is read back through the `READ_FILE` iterator
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.ins_pop_str().unwrap(), "hello world");
+    }
+
+    #[test]
+    fn read_stdin() {
+        let program = "MAIN:\nCALL READ_STDIN\nWRITE_VAR 'it'\nREAD_VAR 'it'\nCALL READ_FILE_NEXT\n\
+                       READ_VAR 'it'\nCALL READ_FILE_NEXT\nREAD_VAR 'it'\nCALL READ_FILE_NEXT\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_stdin(Box::new(io::Cursor::new("first\nsecond\n")));
+        vm.run().unwrap();
+
+        // Iterator is exhausted after two lines
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.ins_pop_str().unwrap(), "second");
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.ins_pop_str().unwrap(), "first");
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn jz_jnz() {
+        // `JZ` jumps over `LOAD_VAL 1` only when the popped value is zero
+        let program = "MAIN:\nLOAD_VAL 0\nJZ -2\nLOAD_VAL 1\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.sp, -1);
+
+        let program = "MAIN:\nLOAD_VAL 3\nJZ -2\nLOAD_VAL 1\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.sp, 0);
+        assert_eq!(vm.stack[0], 1);
+
+        // `JNZ` is the inverse
+        let program = "MAIN:\nLOAD_VAL -3\nJNZ -2\nLOAD_VAL 1\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.sp, -1);
+
+        let program = "MAIN:\nLOAD_VAL 0\nJNZ -2\nLOAD_VAL 1\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.sp, 0);
+        assert_eq!(vm.stack[0], 1);
+    }
+
+    #[test]
+    fn sign() {
+        for (input, sign) in [(-42, -1), (0, 0), (7, 1)] {
+            let program = format!("MAIN:\nLOAD_VAL {}\nSIGN", input);
+            let vm = run_until_instruction(&program, Instruction::Sign).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], sign);
+            assert_eq!(vm.sp, 0);
+        }
+    }
+
+    #[test]
+    fn min_max() {
+        for (lhs, rhs, min, max) in [(3, 8, 3, 8), (8, 3, 3, 8), (-5, -5, -5, -5)] {
+            let program = format!("MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nMIN", lhs, rhs);
+            let vm = run_until_instruction(&program, Instruction::Min).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], min);
+            assert_eq!(vm.sp, 0);
+
+            let program = format!("MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nMAX", lhs, rhs);
+            let vm = run_until_instruction(&program, Instruction::Max).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], max);
+            assert_eq!(vm.sp, 0);
+        }
+    }
+
+    #[test]
+    fn abs() {
+        for (input, abs) in [(-42, 42), (42, 42)] {
+            let program = format!("MAIN:\nLOAD_VAL {}\nABS", input);
+            let vm = run_until_instruction(&program, Instruction::Abs).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], abs);
+        }
+
+        let program = format!("MAIN:\nLOAD_VAL {}\nABS\nHALT 0", i64::MIN);
+        let mut vm = BciVm::load(&program).unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn pow() {
+        let program = "MAIN:\nLOAD_VAL 2\nLOAD_VAL 10\nPOW";
+        let vm = run_until_instruction(program, Instruction::Pow).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 1024);
+        assert_eq!(vm.sp, 0);
+
+        let program = "MAIN:\nLOAD_VAL 2\nLOAD_VAL -1\nPOW\nHALT 0";
+        let err = BciVm::load(program).unwrap().run().unwrap_err();
+        assert!(err.to_string().contains("Negative exponent"));
+
+        let program = "MAIN:\nLOAD_VAL 2\nLOAD_VAL 63\nPOW\nHALT 0";
+        let err = BciVm::load(program).unwrap().run().unwrap_err();
+        assert!(err.to_string().contains("overflow"));
+
+        // Exponents that do not fit in `u32` are not truncated
+        let program = "MAIN:\nLOAD_VAL 2\nLOAD_VAL 4294967296\nPOW\nHALT 0";
+        let err = BciVm::load(program).unwrap().run().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Arithmetic overflow: 2 ^ 4294967296"));
+    }
+
+    #[test]
+    fn comments() {
+        let program =
+            "# computes 2 + 3\nMAIN: # entry point\nLOAD_VAL 2 # lhs\nLOAD_VAL 3\nADD\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 5);
+    }
+
+    #[test]
+    fn dump_stack() {
+        let program =
+            "MAIN:\nCALL DUMP_STACK\nLOAD_VAL 3\nLOAD_VAL -1\nLOAD_VAL 7\nCALL DUMP_STACK\nHALT 0";
+        let (vm, output) = run_with_output(program);
+        assert_eq!(output, ">>>>> stack: []\n>>>>> stack: [3, -1, 7]\n");
+        assert_eq!(&vm.stack[..3], &[3, -1, 7]);
+        assert_eq!(vm.sp, 2);
+    }
+
+    #[test]
+    fn max_call_depth() {
+        let program = "RECURSE:\nCALL RECURSE\nRETURN\n\nMAIN:\nCALL RECURSE\nHALT 0";
+
+        let mut vm = BciVm::load(program).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.to_string(), "line 2: maximum recursion depth exceeded");
+        assert_eq!(vm.frame_stack.len(), DEFAULT_MAX_CALL_DEPTH);
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_max_call_depth(10);
+        assert!(vm.run().is_err());
+        assert_eq!(vm.frame_stack.len(), 10);
+    }
+
+    #[test]
+    fn nested_frames() {
+        let dir = TestDir::new("nested_frames");
+        let path = dir.join("file.txt");
+        fs::write(&path, "outer\ninner\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        // Both functions keep a file iterator and a variable in their own frames
+        let program = format!(
+            "INNER:\nLOAD_VAL 2\nWRITE_VAR 'x'\nPUSH_STR '{path}'\nCALL READ_FILE\nCALL READ_FILE_NEXT\nWRITE_VAR 'ok'\nPOP_STR\nPOP_STR\nREAD_VAR 'x'\nRETURN_VALUE\n\n\
+             MAIN:\nLOAD_VAL 1\nWRITE_VAR 'x'\nPUSH_STR '{path}'\nCALL READ_FILE\nWRITE_VAR 'it'\nPOP_STR\nCALL INNER\nREAD_VAR 'x'\n\
+             READ_VAR 'it'\nCALL READ_FILE_NEXT\nHALT 0"
+        );
+
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.frame_stack.len(), 1);
+        assert_eq!(vm.current_frame().unwrap().local_vars.get("x"), Some(&1));
+        assert_eq!(vm.current_frame().unwrap().dynamic_objects.len(), 1);
+
+        // MAIN's iterator is not affected by the one created in INNER
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.ins_pop_str().unwrap(), "outer");
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.pop_stack().unwrap(), 2);
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn empty_main() {
+        for program in ["MAIN:", "MAIN:\n", "\nMAIN:\n\n"] {
+            let mut vm = BciVm::load(program).unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.halt, Some(0));
+            assert_eq!(vm.sp, -1);
+        }
+    }
+
+    #[test]
+    fn builtin_params() {
+        let run = |body: &str| {
+            let program = format!("MAIN:\n{}\nHALT 0", body);
+            let err = BciVm::load(&program).unwrap().run().unwrap_err();
+            // Without the line of the failing instruction
+            err.downcast::<RuntimeError>().unwrap().error.to_string()
+        };
+
+        assert_eq!(
+            run("CALL READ_FILE"),
+            "READ_FILE expects a string on the stack"
+        );
+        assert_eq!(
+            run("LOAD_VAL -5\nCALL READ_FILE"),
+            "READ_FILE expects a string on the stack"
+        );
+        // Length of the string is bigger than the stack
+        assert_eq!(
+            run("LOAD_VAL 40\nCALL PRINT_STR"),
+            "PRINT_STR expects a string on the stack"
+        );
+        assert_eq!(run("CALL PRINT"), "PRINT expects a number on the stack");
+        assert_eq!(
+            run("PUSH_STR 'content'\nCALL WRITE_FILE"),
+            "WRITE_FILE expects a string and a string on the stack"
+        );
+    }
+
+    #[test]
+    fn i64_values() {
+        let program = format!(
+            "MAIN:\nLOAD_VAL {}\nLOAD_VAL 3\nMUL\nINCR\nWRITE_VAR 'big'\nHALT 0",
+            i32::MAX
+        );
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(
+            vm.current_frame().unwrap().local_vars.get("big"),
+            Some(&(i32::MAX as i64 * 3 + 1))
+        );
+
+        // Jump offsets still have to fit in 32 bits
+        assert!(BciVm::load("MAIN:\nJMP 9999999999").is_err());
+    }
+
+    #[test]
+    fn je_str_jne_str() {
+        // Jumps over `LOAD_VAL 1` when the branch is taken
+        let run = |jump: &str, lhs: &str, rhs: &str| {
+            let program = format!(
+                "MAIN:\nPUSH_STR '{}'\nPUSH_STR '{}'\n{} -2\nLOAD_VAL 1\nHALT 0",
+                lhs, rhs, jump
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run().unwrap();
+            vm.sp == -1
+        };
+
+        assert!(run("JE_STR", "hello", "hello"));
+        assert!(!run("JE_STR", "hello", "world"));
+        assert!(!run("JE_STR", "hello", "hello world"));
+        assert!(run("JNE_STR", "hello", "world"));
+        assert!(!run("JNE_STR", "hello", "hello"));
+    }
+
+    #[test]
+    fn capabilities() {
+        let dir = TestDir::new("capabilities");
+        let path = dir.join("file.txt");
+        fs::write(&path, "line\n").unwrap();
+        let program = format!(
+            "MAIN:\nPUSH_STR '{}'\nCALL READ_FILE\nHALT 0",
+            path.to_str().unwrap()
+        );
+
+        let mut vm = BciVm::load_with_capabilities(&program, &[Capability::Io]).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.to_string(), "line 3: capability 'fs' not enabled");
+
+        let mut vm = BciVm::load_with_capabilities(&program, &[Capability::Fs]).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.current_frame().unwrap().dynamic_objects.len(), 1);
+
+        // String processing is not part of `math`
+        let program = "MAIN:\nPUSH_STR 'abc'\nCALL HASH_STR\nHALT 0";
+        let mut vm = BciVm::load_with_capabilities(program, &[Capability::Math]).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.to_string(), "line 3: capability 'str' not enabled");
+
+        let mut vm = BciVm::load_with_capabilities(program, &[Capability::Str]).unwrap();
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn traverse_dir_sorted() {
+        let test_dir = TestDir::new("traverse_dir_sorted");
+        let dir = test_dir.join("dir");
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["b.txt", "c.md", "a.rs"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        // Print the path of each item, discarding the other information
+        let next = "READ_VAR 'it'\nCALL TRAVERSE_DIR_NEXT\nWRITE_VAR 'some'\nWRITE_VAR 'is_dir'\nPOP_STR\nCALL PRINT_STR\n";
+        let program = format!(
+            "MAIN:\nPUSH_STR '{}'\nCALL TRAVERSE_DIR_SORTED\nWRITE_VAR 'it'\n{}READ_VAR 'it'\nCALL TRAVERSE_DIR_NEXT\nHALT 0",
+            dir.to_str().unwrap(),
+            next.repeat(3)
+        );
+        let (mut vm, output) = run_with_output(&program);
+
+        let expected: Vec<String> = ["a.rs", "b.txt", "c.md"]
+            .iter()
+            .map(|name| format!(">>>>> {}", dir.join(name).to_str().unwrap()))
+            .collect();
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        // No items left
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+    }
+
+    #[test]
+    fn traverse_dir_recursive() {
+        let test_dir = TestDir::new("traverse_dir_recursive");
+        let dir = test_dir.join("dir");
+        fs::create_dir_all(dir.join("sub").join("deeper")).unwrap();
+        for name in ["a.txt", "sub/b.txt", "sub/deeper/c.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        // Prints the path of every item. Directories don't have extensions here.
+        let program = format!(
+            r"
+            MAIN:
+            PUSH_STR '{}'
+            CALL TRAVERSE_DIR_RECURSIVE
+            WRITE_VAR 'it'
+            READ_VAR 'it'
+            CALL TRAVERSE_DIR_NEXT
+            JZ -8
+            JNZ -4
+            POP_STR
+            CALL PRINT_STR
+            JMP 6
+            WRITE_VAR 'ext'
+            CALL PRINT_STR
+            JMP 9
+            HALT 0
+            ",
+            dir.to_str().unwrap()
+        );
+        let (_, output) = run_with_output(&program);
+
+        let mut visited: Vec<&str> = output.lines().collect();
+        visited.sort();
+        let expected: Vec<String> = [
+            "a.txt",
+            "sub",
+            "sub/b.txt",
+            "sub/deeper",
+            "sub/deeper/c.txt",
+        ]
+        .iter()
+        .map(|name| format!(">>>>> {}", dir.join(name).to_str().unwrap()))
+        .collect();
+        assert_eq!(visited, expected);
+    }
+
+    #[test]
+    fn print_raw() {
+        let (vm, output) = run_with_output("MAIN:\nLOAD_VAL 42\nCALL PRINT_RAW\nHALT 0");
+        assert_eq!(output, "42\n");
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn get_arg() {
+        let program = "MAIN:\nLOAD_VAL 1\nCALL GET_ARG\nLOAD_VAL 2\nCALL GET_ARG\nLOAD_VAL -1\nCALL GET_ARG\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_args(vec!["first".to_string(), "second".to_string()]);
+        vm.run().unwrap();
+
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.ins_pop_str().unwrap(), "second");
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn str_vars() {
+        let program = r"
+            OTHER:
+            PUSH_STR 'other'
+            WRITE_STR_VAR 'name'
+            READ_STR_VAR 'name'
+            CALL PRINT_STR
+            RETURN
+
+            MAIN:
+            PUSH_STR 'hello world'
+            WRITE_STR_VAR 'name'
+            CALL OTHER
+            READ_STR_VAR 'name'
+            READ_STR_VAR 'name'
+            CALL PRINT_STR
+            HALT 0
+        ";
+        let (mut vm, output) = run_with_output(program);
+
+        // `OTHER` has its own 'name', which doesn't overwrite the one in `MAIN`
+        assert_eq!(output, ">>>>> other\n>>>>> hello world\n");
+        assert_eq!(vm.ins_pop_str().unwrap(), "hello world");
+        assert_eq!(vm.sp, -1);
+
+        let program = "F:\nREAD_STR_VAR 'name'\nRETURN\n\nMAIN:\nPUSH_STR 'x'\nWRITE_STR_VAR 'name'\nCALL F\nHALT 0";
+        let err = BciVm::load(program).unwrap().run().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: String variable 'name' does not exist."
+        );
+    }
+
+    #[test]
+    fn execution_metrics() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 3
+            WRITE_VAR 'i'
+            READ_VAR 'i'
+            JZ -4
+            READ_VAR 'i'
+            DECR
+            JMP 5
+            HALT 0
+        ";
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        let metrics = vm.metrics();
+        assert_eq!(metrics.count("JMP"), 3);
+        assert_eq!(metrics.count("JZ"), 4);
+        assert_eq!(metrics.count("READ_VAR"), 7);
+        assert_eq!(metrics.count("WRITE_VAR"), 4);
+        assert_eq!(metrics.count("MUL"), 0);
+        // The `MAIN:` label is a `NOP`
+        assert_eq!(metrics.count("NOP"), 1);
+        // CALL MAIN, NOP, LOAD_VAL, 4 loop checks, 3 loop bodies and HALT
+        assert_eq!(metrics.total_instructions, 1 + 1 + 1 + 4 * 3 + 3 * 3 + 1);
+        assert_eq!(
+            metrics.per_instruction.values().sum::<u64>(),
+            metrics.total_instructions
+        );
+    }
+
+    #[test]
+    fn drop_n() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 1
+            LOAD_VAL 2
+            LOAD_VAL 3
+            LOAD_VAL 4
+            LOAD_VAL 5
+            DROP_N 3
+            HALT 0
+        ";
+        let vm = run_until_instruction(program, Instruction::DropN(0)).unwrap();
+        assert_eq!(vm.sp, 1);
+        assert_eq!(vm.stack[vm.sp as usize], 2);
+
+        let program = "MAIN:\nLOAD_VAL 1\nDROP_N 2\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn host_variables() {
+        let program = r"
+            MAIN:
+            READ_VAR 'input'
+            INCR
+            WRITE_VAR 'output'
+            HALT 0
+        ";
+
+        let mut vm = BciVm::load(program).unwrap();
+        // There is no frame before `CALL MAIN` is executed
+        assert!(vm.set_var("input", 41).is_err());
+        assert_eq!(vm.get_var("input"), None);
+
+        vm.next_instruction().unwrap();
+        vm.set_var("input", 41).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_var("output"), Some(42));
+        assert_eq!(vm.get_var("missing"), None);
+    }
+
+    #[test]
+    fn read_var_or() {
+        let program = r"
+            MAIN:
+            READ_VAR_OR 'x' -7
+            LOAD_VAL 5
+            WRITE_VAR 'x'
+            READ_VAR_OR 'x' -7
+            HALT 0
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.sp, 1);
+        assert_eq!(vm.stack[0], -7);
+        assert_eq!(vm.stack[1], 5);
+    }
+
+    #[test]
+    fn logical_and_or() {
+        for (lhs, rhs, and, or) in [(0, 0, 0, 0), (0, 5, 0, 1), (5, 0, 0, 1), (-3, 5, 1, 1)] {
+            let program = format!("MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nAND_B", lhs, rhs);
+            let vm = run_until_instruction(&program, Instruction::AndB).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], and);
+            assert_eq!(vm.sp, 0);
+
+            let program = format!("MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nOR_B", lhs, rhs);
+            let vm = run_until_instruction(&program, Instruction::OrB).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], or);
+            assert_eq!(vm.sp, 0);
+        }
+    }
+
+    #[test]
+    fn function_pointers() {
+        let program = "\n\n\nFIRST:\nLOAD_VAL 1\nRETURN_VALUE\n\nSECOND:\nLOAD_VAL 2\nRETURN_VALUE\nMAIN:\nCALL SECOND\nCALL FIRST\nHALT 0";
+        let vm = BciVm::load(program).unwrap();
+        for name in ["FIRST", "SECOND", "MAIN"] {
+            let ptr = vm.bytecode.fn_table[name].ptr;
+            assert_eq!(vm.bytecode.instructions[ptr], Instruction::Nop);
+            assert_ne!(vm.bytecode.instructions[ptr + 1], Instruction::Nop);
+        }
+
+        let (vm, _) = run_with_output(program);
+        assert_eq!(vm.sp, 1);
+        assert_eq!(vm.stack[0], 2);
+        assert_eq!(vm.stack[1], 1);
+    }
+
+    #[test]
+    fn return_values() {
+        let program = r"
+            DIV_MOD:
+            LOAD_VAL 17
+            LOAD_VAL 3
+            LOAD_VAL 5
+            LOAD_VAL 2
+            RETURN_VALUES 2
+
+            MAIN:
+            LOAD_VAL 100
+            CALL DIV_MOD
+            WRITE_VAR 'remainder'
+            WRITE_VAR 'quotient'
+            HALT 0
+        ";
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_var("quotient"), Some(5));
+        assert_eq!(vm.get_var("remainder"), Some(2));
+        // Values below the returned ones are left untouched
+        assert_eq!(vm.sp, 2);
+        assert_eq!(&vm.stack[..3], &[100, 17, 3]);
+
+        let program = "F:\nLOAD_VAL 1\nRETURN_VALUES 2\nMAIN:\nCALL F\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn assert_eq() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 4
+            LOAD_VAL 4
+            ASSERT_EQ
+            LOAD_VAL 4
+            LOAD_VAL 5
+            ASSERT_EQ
+            LOAD_VAL 6
+            HALT 0
+        ";
+        let (vm, output) = run_with_output(program);
+
+        assert_eq!(vm.halt, Some(ASSERTION_FAILED_EXIT_CODE));
+        assert_eq!(output, ">>>>> assertion failed at instruction 9: 4 != 5\n");
+        // The program is halted right after the failing assertion
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn host_functions() {
+        let program = r"
+            MAIN:
+            CALL COUNT
+            CALL COUNT
+            CALL COUNT
+            HALT 0
+        ";
+
+        let counter = Rc::new(RefCell::new(0));
+        let mut vm = BciVm::load(program).unwrap();
+        let calls = Rc::clone(&counter);
+        vm.register_fn("COUNT", move |vm| {
+            *calls.borrow_mut() += 1;
+            vm.set_var("calls", *calls.borrow())
+        });
+        vm.run().unwrap();
+
+        assert_eq!(*counter.borrow(), 3);
+        assert_eq!(vm.get_var("calls"), Some(3));
+    }
+
+    #[test]
+    fn listing() {
+        let program = "MAIN:\nLOAD_VAL 1\nJNZ -2\nJMP 2\nHALT 0";
+        let bytecode = Parser::new(program).parse().unwrap();
+
+        assert_eq!(
+            bytecode.listing(),
+            "0000: Call(\"MAIN\")\n\
+             0001: Exit\n\
+             0002: Nop\n\
+             0003: LoadVal(1)\n\
+             0004: Jnz(-2)  ; -> 0006\n\
+             0005: Jmp(2)  ; -> 0003\n\
+             0006: Halt(0)\n"
+        );
+    }
+
+    #[test]
+    fn str_slice() {
+        let (_, output) = run_with_output(
+            "MAIN:\nPUSH_STR 'hello world'\nLOAD_VAL 6\nLOAD_VAL 11\nCALL STR_SLICE\nCALL PRINT_STR",
+        );
+        assert_eq!(output, ">>>>> world\n");
+
+        let program = "MAIN:\nPUSH_STR 'hello'\nLOAD_VAL 2\nLOAD_VAL 2\nCALL STR_SLICE\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.ins_pop_str().unwrap(), "");
+        assert_eq!(vm.sp, -1);
+
+        for (start, end) in [(0, 6), (3, 2), (-1, 2)] {
+            let program = format!(
+                "MAIN:\nPUSH_STR 'hello'\nLOAD_VAL {}\nLOAD_VAL {}\nCALL STR_SLICE\nHALT 0",
+                start, end
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            assert!(vm.run().is_err());
+        }
+    }
+
+    #[test]
+    fn str_split() {
+        let program = r"
+            MAIN:
+            PUSH_STR 'a,b,c'
+            PUSH_STR ','
+            CALL STR_SPLIT
+            WRITE_VAR 'pieces'
+            READ_VAR 'pieces'
+            CALL STR_SPLIT_NEXT
+            JZ -3
+            CALL PRINT_STR
+            JMP 4
+            HALT 0
+        ";
+        let (vm, output) = run_with_output(program);
+
+        assert_eq!(output, ">>>>> a\n>>>>> b\n>>>>> c\n");
+        assert_eq!(vm.sp, -1);
+
+        // Empty delimiter
+        let program = "MAIN:\nPUSH_STR 'abc'\nPUSH_STR 'abc'\nLOAD_VAL 0\nLOAD_VAL 0\nCALL STR_SLICE\nCALL STR_SPLIT\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn root_jail() {
+        // The jail is a directory next to the file that should not be reachable
+        let dir = TestDir::new("root_jail");
+        let jail = dir.join("jail");
+        fs::create_dir_all(jail.join("inner")).unwrap();
+        fs::write(jail.join("inner/file.txt"), "inside\n").unwrap();
+        fs::write(dir.join("outside.txt"), "outside\n").unwrap();
+
+        let run_in_jail = |program: &str| {
+            let mut vm = BciVm::load(program).unwrap();
+            vm.set_writer(Box::new(io::sink()));
+            vm.set_root_jail(jail.clone()).unwrap();
+            vm.run()
+        };
+
+        // Relative paths are resolved from the jail
+        let program =
+            "MAIN:\nPUSH_STR 'inner/file.txt'\nCALL READ_FILE\nCALL READ_FILE_NEXT\nHALT 0";
+        assert!(run_in_jail(program).is_ok());
+        let program =
+            "MAIN:\nPUSH_STR 'inner/../inner/new.txt'\nPUSH_STR 'x'\nCALL WRITE_FILE\nHALT 0";
+        assert!(run_in_jail(program).is_ok());
+        assert!(jail.join("inner/new.txt").exists());
+
+        for path in ["../outside.txt", "/etc", "inner/../../outside.txt"] {
+            let program = format!("MAIN:\nPUSH_STR '{}'\nCALL READ_FILE\nHALT 0", path);
+            assert!(run_in_jail(&program).is_err(), "{}", path);
+            let program = format!("MAIN:\nPUSH_STR '{}'\nCALL TRAVERSE_DIR\nHALT 0", path);
+            assert!(run_in_jail(&program).is_err(), "{}", path);
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir.0, jail.join("escape")).unwrap();
+            let program = "MAIN:\nPUSH_STR 'escape/outside.txt'\nCALL READ_FILE\nHALT 0";
+            assert!(run_in_jail(program).is_err());
+        }
+    }
+
+    #[test]
+    fn run_with_timeout() {
+        let timeout = Duration::from_millis(50);
+
+        let mut vm = BciVm::load("MAIN:\nNOP\nJMP 1").unwrap();
+        let err = vm.run_with_timeout(timeout).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TimeoutError>(),
+            Some(&TimeoutError { timeout })
+        );
+        assert_eq!(vm.halt, None);
+
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 1\nHALT 0").unwrap();
+        vm.run_with_timeout(timeout).unwrap();
+        assert_eq!(vm.halt, Some(0));
+    }
+
+    #[test]
+    fn jge_jle() {
+        // Pushes 1 if the jump is taken, 0 otherwise
+        for (lhs, rhs, jge, jle) in [(1, 2, 0, 1), (2, 2, 1, 1), (3, 2, 1, 0)] {
+            for (keyword, expected) in [("JGE", jge), ("JLE", jle)] {
+                let program = format!(
+                    "MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nCMP\n{} -3\nLOAD_VAL 0\nHALT 0\nLOAD_VAL 1\nHALT 0",
+                    lhs, rhs, keyword
+                );
+                let mut vm = BciVm::load(&program).unwrap();
+                vm.run().unwrap();
+                assert_eq!(
+                    vm.stack[vm.sp as usize], expected,
+                    "{} {} {}",
+                    lhs, keyword, rhs
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn print_instruction() {
+        let (_, output) =
+            run_with_output("MAIN:\nLOAD_VAL 7\nPRINT\nLOAD_VAL 8\nCALL PRINT\nHALT 0");
+        assert_eq!(output, ">>>>> 7\n>>>>> 8\n");
+
+        // Same as the built-in function, it needs the `io` capability
+        let mut vm =
+            BciVm::load_with_capabilities("MAIN:\nLOAD_VAL 7\nPRINT\nHALT 0", &[Capability::Math])
+                .unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn eval_line() {
+        let mut vm = BciVm::from_bytecode(Bytecode::default());
+
+        vm.eval_line("LOAD_VAL 2").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[2]);
+        vm.eval_line("LOAD_VAL 3").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[2, 3]);
+        vm.eval_line("ADD").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[5]);
+        vm.eval_line("WRITE_VAR 'x'").unwrap();
+        assert_eq!(vm.sp, -1);
+        vm.eval_line("").unwrap();
+        vm.eval_line("READ_VAR 'x'").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[5]);
+
+        assert!(vm.eval_line("JMP 2").is_err());
+        assert!(vm.eval_line("MAIN:").is_err());
+        assert!(vm.eval_line("INCR\nINCR").is_err());
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[5]);
+
+        // Functions of a loaded program run until they return
+        let mut vm = BciVm::load("DOUBLE:\nLOAD_VAL 2\nMUL\nRETURN_VALUE\nMAIN:\nHALT 0").unwrap();
+        vm.eval_line("LOAD_VAL 21").unwrap();
+        vm.eval_line("CALL DOUBLE").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[42]);
+        assert_eq!(vm.halt, None);
+
+        // A function that fails does not leave the session in its frame
+        let mut vm = BciVm::load(
+            "FAIL:\nLOAD_VAL 1\nWRITE_VAR 'y'\nREAD_VAR 'missing'\nRETURN\nMAIN:\nHALT 0",
+        )
+        .unwrap();
+        vm.eval_line("LOAD_VAL 5").unwrap();
+        vm.eval_line("WRITE_VAR 'x'").unwrap();
+        assert!(vm.eval_line("CALL FAIL").is_err());
+        assert_eq!(vm.frame_stack.len(), 1);
+        vm.eval_line("READ_VAR 'x'").unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[5]);
+        assert!(vm.eval_line("READ_VAR 'y'").is_err());
+    }
+
+    #[test]
+    fn cmp_flags() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 4
+            LOAD_VAL 4
+            CMP
+            LOAD_VAL 99
+            JE -3
+            LOAD_VAL 0
+            HALT 0
+            LOAD_VAL 1
+            HALT 0
+        ";
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_cmp_mode(CmpMode::Flags);
+        vm.run().unwrap();
+        // The pushed value stays on stack and the jump is taken
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[99, 1]);
+
+        // On stack, `JE` pops the pushed value instead of the result of `CMP`
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[0, 0]);
+
+        let program =
+            "MAIN:\nPUSH_STR 'a'\nPUSH_STR 'b'\nCMP_STR\nLOAD_VAL 7\nJL -2\nHALT 1\nHALT 0\nHALT 2";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_cmp_mode(CmpMode::Flags);
+        vm.run().unwrap();
+        assert_eq!(vm.halt, Some(0));
+
+        let mut vm = BciVm::load("MAIN:\nJG 2\nHALT 0").unwrap();
+        vm.set_cmp_mode(CmpMode::Flags);
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn add_sub_imm() {
+        let vm =
+            run_until_instruction("MAIN:\nLOAD_VAL 10\nADD_IMM 5", Instruction::AddImm(0)).unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 15);
+        assert_eq!(vm.sp, 0);
+
+        let vm = run_until_instruction("MAIN:\nLOAD_VAL 10\nSUB_IMM -5", Instruction::SubImm(0))
+            .unwrap();
+        assert_eq!(vm.stack[vm.sp as usize], 15);
+        assert_eq!(vm.sp, 0);
+
+        let program = format!("MAIN:\nLOAD_VAL {}\nADD_IMM 1\nHALT 0", i64::MAX);
+        let mut vm = BciVm::load(&program).unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn bool() {
+        for (input, expected) in [(0, 0), (42, 1), (-7, 1)] {
+            let program = format!("MAIN:\nLOAD_VAL {}\nBOOL", input);
+            let vm = run_until_instruction(&program, Instruction::Bool).unwrap();
+            assert_eq!(vm.stack[vm.sp as usize], expected);
+            assert_eq!(vm.sp, 0);
+        }
+    }
+
+    #[test]
+    fn with_inputs() {
+        let program = "MAIN:\nREAD_VAR 'inp'\nADD_IMM 1\nWRITE_VAR 'out'\nHALT 0";
+        let inputs = HashMap::from([("inp".to_string(), 41)]);
+
+        let mut vm = BciVm::with_inputs(program, inputs).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_var("out"), Some(42));
+    }
+
+    #[test]
+    fn load_file() {
+        let dir = TestDir::new("load_file");
+        let path = dir.join("program.bci");
+
+        fs::write(&path, "MAIN:\nLOAD_VAL 3\nHALT 0").unwrap();
+        let mut vm = BciVm::load_file(&path).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.stack[0], 3);
+
+        fs::write(&path, "MAIN:\nLOAD_VAL 'three'\nHALT 0").unwrap();
+        let err = BciVm::load_file(&path).err().unwrap();
+        assert!(format!("{:#}", err).contains("program.bci"));
+
+        fs::remove_file(&path).unwrap();
+        let err = BciVm::load_file(&path).err().unwrap();
+        assert!(format!("{:#}", err).contains("program.bci"));
+    }
+
+    #[test]
+    fn owns_the_program() {
+        fn load() -> BciVm {
+            let program = String::from("MAIN:\nPUSH_STR 'owned'\nCALL PRINT_STR\nHALT 0");
+            BciVm::load(&program).unwrap()
+            // `program` is dropped here
+        }
+
+        let mut vm = load();
+        let output = SharedBuf::default();
+        vm.set_writer(Box::new(output.clone()));
+        vm.run().unwrap();
+        assert_eq!(output.contents(), ">>>>> owned\n");
+    }
+
+    #[test]
+    fn swap_var() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 1
+            WRITE_VAR 'a'
+            LOAD_VAL 2
+            WRITE_VAR 'b'
+            SWAP_VAR 'a' 'b'
+            HALT 0
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_var("a"), Some(2));
+        assert_eq!(vm.get_var("b"), Some(1));
+
+        let program = "MAIN:\nLOAD_VAL 1\nWRITE_VAR 'a'\nSWAP_VAR 'a' 'b'\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+        assert_eq!(vm.get_var("a"), Some(1));
+    }
+
+    #[test]
+    fn stack_size() {
+        let program = "MAIN:\nSTACK_SIZE\nLOAD_VAL 7\nLOAD_VAL 8\nSTACK_SIZE\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[0, 7, 8, 3]);
+    }
+
+    #[test]
+    fn print_hex() {
+        let (_, output) =
+            run_with_output("MAIN:\nLOAD_VAL 31\nCALL PRINT_HEX\nLOAD_VAL -1\nCALL PRINT_HEX");
+        assert_eq!(output, ">>>>> 0x1f\n>>>>> 0xffffffffffffffff\n");
+    }
+
+    #[test]
+    fn load_char() {
+        let program = r"
+            MAIN:
+            LOAD_CHAR 'A'
+            LOAD_CHAR 'é'
+            LOAD_CHAR '\n'
+            LOAD_CHAR '\\'
+            LOAD_CHAR ' '
+            HALT 0
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[65, 233, 10, 92, 32]);
+
+        for literal in ["AB", "\\q"] {
+            let program = format!("MAIN:\nLOAD_CHAR '{}'", literal);
+            assert!(BciVm::load(&program).is_err(), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn jeq_imm() {
+        // Counts down from 3 and pushes every visited value
+        let fused = r"
+            MAIN:
+            LOAD_VAL 3
+            WRITE_VAR 'i'
+            READ_VAR 'i'
+            JEQ_IMM 0 -6
+            READ_VAR 'i'
+            READ_VAR 'i'
+            DECR
+            WRITE_VAR 'i'
+            JMP 6
+            HALT 0
+        ";
+        let unfused = r"
+            MAIN:
+            LOAD_VAL 3
+            WRITE_VAR 'i'
+            READ_VAR 'i'
+            LOAD_VAL 0
+            CMP
+            JE -6
+            READ_VAR 'i'
+            READ_VAR 'i'
+            DECR
+            WRITE_VAR 'i'
+            JMP 8
+            HALT 0
+        ";
+
+        let mut fused_vm = BciVm::load(fused).unwrap();
+        fused_vm.run().unwrap();
+        let mut unfused_vm = BciVm::load(unfused).unwrap();
+        unfused_vm.run().unwrap();
+
+        assert_eq!(&fused_vm.stack[..=fused_vm.sp as usize], &[3, 2, 1]);
+        assert_eq!(
+            &fused_vm.stack[..=fused_vm.sp as usize],
+            &unfused_vm.stack[..=unfused_vm.sp as usize]
+        );
+        assert_eq!(fused_vm.halt, Some(0));
+    }
+
+    #[test]
+    fn try_catch() {
+        let program = r"
+            OPEN:
+            PUSH_STR '/bci/does/not/exist'
+            CALL READ_FILE
+            RETURN
+
+            MAIN:
+            LOAD_VAL 7
+            TRY_BEGIN -6
+            LOAD_VAL 8
+            CALL OPEN
+            TRY_END
+            LOAD_VAL 0
+            HALT 0
+            WRITE_VAR 'error'
+            HALT 3
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        // The stack and the frame of `OPEN` are unwound and the handler continues in `MAIN`
+        assert_eq!(vm.halt, Some(3));
+        assert_eq!(vm.get_var("error"), Some(CAUGHT_ERROR_CODE));
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[7]);
+        assert_eq!(vm.frame_stack.len(), 1);
+
+        // Errors after the region are not caught
+        let program = "MAIN:\nTRY_BEGIN -3\nTRY_END\nADD\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+
+        // A region that is left open by a function that has returned does not catch the errors of
+        // the next function at the same depth
+        let program = r"
+            F:
+            TRY_BEGIN -3
+            RETURN
+            LOAD_VAL 99
+            HALT 42
+
+            G:
+            ADD
+            RETURN
+
+            MAIN:
+            CALL F
+            CALL G
+            HALT 0
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        assert!(vm.run().is_err());
+        assert_eq!(vm.halt, None);
+        assert!(vm.try_handlers.is_empty());
+    }
+
+    #[test]
+    fn file_metadata() {
+        let dir = TestDir::new("file_metadata");
+        let path = dir.join("file.txt");
+        fs::write(&path, "0123456789").unwrap();
+        let path = path.to_str().unwrap();
+        let program = format!(
+            "MAIN:\nPUSH_STR '{path}'\nCALL FILE_SIZE\nWRITE_VAR 'ok'\nWRITE_VAR 'size'\n\
+             PUSH_STR '{path}'\nCALL FILE_MODIFIED\nWRITE_VAR 'mod_ok'\nWRITE_VAR 'modified'\n\
+             PUSH_STR '{path}.missing'\nCALL FILE_SIZE\nHALT 0"
+        );
+
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_var("size"), Some(10));
+        assert_eq!(vm.get_var("ok"), Some(1));
+        assert_eq!(vm.get_var("mod_ok"), Some(1));
+        assert!(vm.get_var("modified").unwrap() > 0);
+
+        // A missing file is reported with the flag
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+    }
+
+    #[test]
+    fn hash_str() {
+        let program =
+            "MAIN:\nPUSH_STR 'foobar'\nCALL HASH_STR\nPUSH_STR 'a'\nCALL HASH_STR\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        // Reference values of FNV-1a
+        assert_eq!(vm.pop_stack().unwrap(), 0xe40c_292c);
+        assert_eq!(vm.pop_stack().unwrap(), 0xbf9c_f968);
+        assert_eq!(fnv1a_32(b""), 0x811c_9dc5);
+    }
+
+    #[test]
+    fn backtrace() {
+        let program = r"
+            INNER:
+            LOAD_VAL 1
+            WRITE_VAR 'inner'
+            ADD
+            RETURN
+
+            OUTER:
+            PUSH_STR 'str'
+            WRITE_STR_VAR 'name'
+            CALL INNER
+            RETURN
+
+            MAIN:
+            LOAD_VAL 1
+            WRITE_VAR 'a'
+            CALL OUTER
+            HALT 0
+        ";
+        let mut vm = BciVm::load(program).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.to_string(), "line 5: Fatal: stack is smaller than 2");
+
+        let backtrace = &err.downcast_ref::<RuntimeError>().unwrap().backtrace;
+        assert_eq!(backtrace, &vm.call_stack());
+        let local_vars: Vec<&[String]> = backtrace.iter().map(|f| &f.local_vars[..]).collect();
+        assert_eq!(
+            local_vars,
+            [
+                &["a".to_string()][..],
+                &["name".to_string()],
+                &["inner".to_string()]
+            ]
+        );
+
+        // `INNER` and `OUTER` return to the instructions after their calls
+        let ret_addrs: Vec<usize> = backtrace.iter().map(|f| f.ret_addr).collect();
+        assert_eq!(ret_addrs[1..], [19, 13]);
+    }
+
+    #[test]
+    fn unknown_instruction() {
+        let err = BciVm::load("MAIN:\nLOAD_VAL 2\nMULT\nHALT 0")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "unknown instruction 'MULT'");
+
+        let mut vm = BciVm::from_bytecode(Bytecode::default());
+        let err = vm.eval_line("LOAD_VALL 1").unwrap_err();
+        assert_eq!(err.to_string(), "unknown instruction 'LOAD_VALL'");
+
+        // Labels are still labels
+        let err = BciVm::load("MAIN:\nmain\nHALT 0").err().unwrap();
+        assert_eq!(err.to_string(), "':' should come after a label");
+    }
+
+    #[test]
+    fn semicolon_separated_instructions() {
+        let bytecode = Parser::new("MAIN:\nLOAD_VAL 1; LOAD_VAL 2\nADD;HALT 0")
+            .parse()
+            .unwrap();
+        assert_eq!(
+            bytecode.instructions[2..],
+            [
+                Instruction::Nop,
+                Instruction::LoadVal(1),
+                Instruction::LoadVal(2),
+                Instruction::Add,
+                Instruction::Halt(0),
+            ]
+        );
+
+        let (vm, output) = run_with_output("MAIN:\nLOAD_VAL 1; LOAD_VAL 2; ADD\nPRINT; HALT 0");
+        assert_eq!(vm.halt, Some(0));
+        assert_eq!(output, ">>>>> 3\n");
+
+        assert!(Parser::new("MAIN:\nLOAD_VAL 1;\nHALT 0").parse().is_err());
+        assert!(Parser::new("MAIN:\nLOAD_VAL 1; MAIN:").parse().is_err());
+    }
+
+    #[test]
+    fn optimize_write_read_pairs() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 3
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            PRINT
+            LOAD_VAL 4
+            WRITE_VAR 'y'
+            READ_VAR 'y'
+            READ_VAR 'x'
+            ADD
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            PRINT
+            LOAD_VAL 9
+            WRITE_VAR 'z'
+            LOAD_VAL 5
+            READ_VAR 'x'
+            LOAD_VAL 7
+            CMP
+            JE -2
+            WRITE_VAR 'z'
+            READ_VAR 'z'
+            HALT 0
+        ";
+        let (vm, output) = run_with_output(program);
+
+        let bytecode = Parser::new(program).parse().unwrap().optimize();
+        let tees = bytecode
+            .instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, Instruction::TeeVar(_)))
+            .count();
+        // The last pair is kept since `JE` jumps to its `READ_VAR`
+        assert_eq!(tees, 3);
+        assert!(bytecode
+            .instructions
+            .contains(&Instruction::ReadVar("z".into())));
+
+        let buf = SharedBuf::default();
+        let mut optimized_vm = BciVm::from_bytecode(bytecode);
+        optimized_vm.set_writer(Box::new(buf.clone()));
+        optimized_vm.run().unwrap();
+
+        assert_eq!(output, ">>>>> 3\n>>>>> 7\n");
+        assert_eq!(buf.contents(), output);
+        assert_eq!(optimized_vm.halt, vm.halt);
+        assert_eq!(optimized_vm.get_var("x"), Some(7));
+        assert_eq!(optimized_vm.get_var("z"), Some(9));
+        assert_eq!(&optimized_vm.stack[..=optimized_vm.sp as usize], &[5, 9]);
+    }
+
+    #[test]
+    fn main_return_value_is_exit_code() {
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 7\nRETURN_VALUE").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.halt, Some(7));
+
+        // A value that is returned by another function does not leak into the exit code
+        let program = "GET:\nLOAD_VAL 3\nRETURN_VALUE\nMAIN:\nCALL GET\nRETURN";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.halt, Some(0));
+
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 4294967296\nRETURN_VALUE").unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn cmp3() {
+        let (_, output) = run_with_output(
+            "MAIN:\nLOAD_VAL 3\nLOAD_VAL 5\nCMP3\nPRINT\n\
+             PUSH_STR 'b'\nPUSH_STR 'a'\nCMP3\nPRINT\n\
+             PUSH_STR 'same'\nPUSH_STR 'same'\nCMP3\nPRINT\nHALT 0",
+        );
+        assert_eq!(output, ">>>>> -1\n>>>>> 1\n>>>>> 0\n");
+
+        // The length of a string is not compared with the number
+        let err = BciVm::load("MAIN:\nLOAD_VAL 1\nPUSH_STR 'a'\nCMP3\nHALT 0")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4: CMP3 cannot compare a number with a string"
+        );
+        let err = BciVm::load("MAIN:\nPUSH_STR 'a'\nLOAD_VAL 1\nCMP3\nHALT 0")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4: CMP3 cannot compare a string with a number"
+        );
+    }
+
+    #[test]
+    fn source_lines() {
+        let program =
+            "# comment\nMAIN:\nLOAD_VAL 1; WRITE_VAR 'x'\n\nREAD_VAR 'x'\nREAD_VAR 'foo'\nHALT 0";
+        let bytecode = Parser::new(program).parse().unwrap();
+        assert_eq!(bytecode.source_lines, [0, 0, 1, 2, 3, 3, 4, 5, 6, 7]);
+
+        let err = BciVm::load(program).unwrap().run().unwrap_err();
+        assert_eq!(err.to_string(), "line 6: Variable 'foo' does not exist.");
+
+        // Lines are kept along with the instructions while optimizing
+        let bytecode = Parser::new(program).parse().unwrap().optimize();
+        assert_eq!(bytecode.source_lines.len(), bytecode.instructions.len());
+        let err = BciVm::from_bytecode(bytecode).run().unwrap_err();
+        assert_eq!(err.to_string(), "line 6: Variable 'foo' does not exist.");
+    }
+
+    #[test]
+    fn sleep_ms() {
+        let slept = Rc::new(RefCell::new(Vec::new()));
+        let mut vm =
+            BciVm::load("MAIN:\nLOAD_VAL 1500\nCALL SLEEP_MS\nLOAD_VAL 0\nCALL SLEEP_MS\nHALT 0")
+                .unwrap();
+        let recorder = Rc::clone(&slept);
+        vm.set_sleep(move |duration| recorder.borrow_mut().push(duration));
+        vm.run().unwrap();
+
+        assert_eq!(
+            *slept.borrow(),
+            [Duration::from_millis(1500), Duration::from_millis(0)]
+        );
+
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL -1\nCALL SLEEP_MS\nHALT 0").unwrap();
+        vm.set_sleep(|_| panic!("should not sleep"));
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn function_names() {
+        let program = "SQUARE:\nRETURN\nADD_ONE:\nRETURN\nMAIN:\nHALT 0";
+        let bytecode = Parser::new(program).parse().unwrap();
+        assert_eq!(bytecode.function_names(), ["ADD_ONE", "MAIN", "SQUARE"]);
+
+        assert!(Bytecode::default().function_names().is_empty());
+    }
+
+    #[test]
+    fn print_str_peek() {
+        let (mut vm, output) = run_with_output(
+            "MAIN:\nLOAD_VAL 7\nPUSH_STR 'hello world'\nCALL PRINT_STR_PEEK\nCALL PRINT_STR_PEEK\nHALT 0",
+        );
+        assert_eq!(output, ">>>>> hello world\n>>>>> hello world\n");
+
+        assert_eq!(vm.ins_pop_str().unwrap(), "hello world");
+        assert_eq!(vm.pop_stack().unwrap(), 7);
+        assert_eq!(vm.sp, -1);
+    }
+
+    #[test]
+    fn max_dynamic_objects() {
+        let program = r"
+            MAIN:
+            LOAD_VAL 0
+            WRITE_VAR 'i'
+            CALL READ_STDIN
+            READ_VAR 'i'
+            INCR
+            WRITE_VAR 'i'
+            JMP 4
+        ";
+
+        // Objects of a returning function are released, so the calls never hit the limit
+        let calls =
+            "MAIN:\nCALL OPEN\nCALL OPEN\nCALL OPEN\nHALT 0\nOPEN:\nCALL READ_STDIN\nRETURN";
+        let mut vm = BciVm::load(calls).unwrap();
+        vm.set_max_dynamic_objects(1);
+        vm.run().unwrap();
+
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_max_dynamic_objects(10);
+        let err = vm.run().unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap().error;
+        assert_eq!(err.to_string(), "too many dynamic objects, the limit is 10");
+        assert_eq!(vm.get_var("i"), Some(10));
+    }
+
+    #[test]
+    fn str_pool() {
+        let program =
+            "MAIN:\nPUSH_STR 'hi'\nPUSH_STR 'other'\nPUSH_STR 'hi'\nLOAD_CONST_STR 0\nHALT 0";
+        let bytecode = Parser::new(program).parse().unwrap();
+        assert_eq!(bytecode.str_pool, ["hi".into(), "other".into()]);
+
+        // Both pushes of the same literal use the string of the pool
+        match (&bytecode.instructions[3], &bytecode.instructions[5]) {
+            (Instruction::PushStr(first), Instruction::PushStr(second)) => {
+                assert!(Rc::ptr_eq(first, second));
+                assert!(Rc::ptr_eq(first, &bytecode.str_pool[0]));
+            }
+            instructions => panic!("unexpected instructions {:?}", instructions),
+        }
+
+        let mut vm = BciVm::from_bytecode(bytecode);
+        vm.run().unwrap();
+        for s in ["hi", "hi", "other", "hi"] {
+            assert_eq!(vm.ins_pop_str().unwrap(), s);
+        }
+
+        let mut vm = BciVm::load("MAIN:\nLOAD_CONST_STR 0\nHALT 0").unwrap();
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn read_file_missing() {
+        let dir = TestDir::new("read_file_missing");
+        let path = dir.join("missing.txt");
+        let path = path.to_str().unwrap();
+
+        let program = format!("MAIN:\nPUSH_STR '{path}'\nCALL READ_FILE\nHALT 0");
+        let err = BciVm::load(&program).unwrap().run().unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap().error;
+        match err.downcast_ref::<BciError>() {
+            Some(BciError::Io {
+                path: err_path,
+                source,
+            }) => {
+                assert_eq!(err_path, path);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // The program branches on the failure instead of ending
+        let program =
+            format!("MAIN:\nTRY_BEGIN -4\nPUSH_STR '{path}'\nCALL READ_FILE\nHALT 0\nHALT 2");
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.halt, Some(2));
+    }
+
+    #[test]
+    fn dump_vars() {
+        let (vm, output) = run_with_output(
+            "MAIN:\nLOAD_VAL 3\nWRITE_VAR 'b'\nLOAD_VAL -1\nWRITE_VAR 'a'\nLOAD_VAL 7\nWRITE_VAR 'c'\n\
+             LOAD_VAL 5\nWRITE_VAR 'b'\nCALL DUMP_VARS\nHALT 0",
+        );
+        assert_eq!(output, ">>>>> a = -1\n>>>>> b = 5\n>>>>> c = 7\n");
+        assert_eq!(vm.sp, -1);
+        assert_eq!(vm.get_var("b"), Some(5));
     }
 
-    // Call a function
-    fn ins_call(&mut self, fn_name: &str) -> anyhow::Result<()> {
-        if self.builtin_fns.contains_key(&fn_name) {
-            return self.builtin_fns[fn_name](self);
-        }
+    #[test]
+    fn pick() {
+        let run = |body: &str| {
+            let program = format!(
+                "MAIN:\nLOAD_VAL 10\nLOAD_VAL 20\nLOAD_VAL 30\n{}\nHALT 0",
+                body
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run().map(|_| vm.stack[..(vm.sp + 1) as usize].to_vec())
+        };
 
-        // See if there is a builtin function
-        let fn_addr = match self.bytecode.fn_table.get(fn_name) {
-            Some(func) => func.ptr,
-            None => return Err(anyhow!("Function '{}' does not exist.", fn_name)),
+        // Top
+        assert_eq!(run("PICK 0").unwrap(), [10, 20, 30, 30]);
+        assert_eq!(run("PICK -3").unwrap(), [10, 20, 30, 30]);
+        // Bottom
+        assert_eq!(run("PICK 2").unwrap(), [10, 20, 30, 10]);
+        assert_eq!(run("PICK -1").unwrap(), [10, 20, 30, 10]);
+        // Just outside of the stack
+        assert!(run("PICK 3").is_err());
+        assert!(run("PICK -4").is_err());
+
+        // The same boundaries for dropping
+        assert_eq!(run("DROP_N 3").unwrap(), []);
+        assert!(run("DROP_N 4").is_err());
+        assert!(BciVm::load("MAIN:\nPICK 0\nHALT 0").unwrap().run().is_err());
+    }
+
+    #[test]
+    fn error_category() {
+        let err = BciVm::load("MAIN:\nADD_IMM 1\nHALT 0")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap();
+        assert_eq!(err.to_string(), "line 2: Fatal: stack is empty.");
+        assert_eq!(err.category(), ErrorCategory::Program);
+
+        // A hand-built bytecode that runs outside of any function
+        let bytecode = Bytecode {
+            instructions: vec![Instruction::TryBegin(-1), Instruction::ReadVar("x".into())],
+            ..Bytecode::default()
         };
+        let err = BciVm::from_bytecode(bytecode).run().unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap();
+        assert_eq!(err.to_string(), "fatal: there is no stack frame");
+        assert_eq!(err.category(), ErrorCategory::Internal);
 
-        // ip + 1: not to call a function forever
-        let stack_frame = StackFrame::new(self.ip + 1);
+        // A handle that does not belong to any dynamic object is a mistake of the program
+        let err = BciVm::load("MAIN:\nLOAD_VAL 42\nCALL STR_SPLIT_NEXT\nHALT 0")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap();
+        assert_eq!(err.to_string(), "line 3: cannot find the dynamic object 42");
+        assert_eq!(err.category(), ErrorCategory::Program);
 
-        self.frame_stack.push(stack_frame);
-        self.fp += 1;
-        self.ip = fn_addr;
-        Ok(())
+        // Overflowing the stack is an error instead of a panic of the host
+        let err = BciVm::load("MAIN:\nLOAD_VAL 1\nJMP 1")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        let err = err.downcast::<RuntimeError>().unwrap();
+        assert_eq!(err.to_string(), "line 2: Fatal: stack is full.");
+        assert_eq!(err.category(), ErrorCategory::Program);
     }
 
-    /// Return from the function by saving the return value
-    fn ins_ret_value(&mut self) -> anyhow::Result<()> {
-        match self.frame_stack.pop() {
-            Some(mut stack_frame) => {
-                stack_frame.ret_value = Some(self.pop_stack()?);
-                self.ip = stack_frame.ret_addr;
-                self.push_stack(stack_frame.ret_value.unwrap());
-                self.fp -= 1;
-                Ok(())
-            }
-            None => Err(anyhow!("Fatal: unexpected return")),
-        }
+    #[test]
+    fn get_cwd() {
+        let mut vm = BciVm::load("MAIN:\nCALL GET_CWD\nHALT 0").unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(vm.ins_pop_str().unwrap(), cwd.to_str().unwrap());
     }
 
-    /// Return from the function
-    fn ins_ret(&mut self) -> anyhow::Result<()> {
-        match self.frame_stack.pop() {
-            Some(stack_frame) => {
-                self.ip = stack_frame.ret_addr;
-                self.fp -= 1;
-                Ok(())
+    #[test]
+    fn switch() {
+        let program = r"
+            MAIN:
+            READ_VAR 'selector'
+            SWITCH -5 -2 -4
+            HALT 10
+            HALT 11
+            HALT 12
+            HALT 13
+            HALT 14
+        ";
+        let run = |selector: i64, optimize: bool| {
+            let mut bytecode = Parser::new(program).parse().unwrap();
+            if optimize {
+                bytecode = bytecode.optimize();
             }
-            None => Err(anyhow!("Fatal: unexpected return")),
+            let mut vm = BciVm::from_bytecode(bytecode);
+            vm.inputs = HashMap::from([("selector".to_string(), selector)]);
+            vm.run().unwrap();
+            vm.halt.unwrap()
+        };
+
+        // Offsets point to the same instructions after optimizing
+        for optimize in [false, true] {
+            assert_eq!(run(0, optimize), 11);
+            assert_eq!(run(1, optimize), 13);
+            // Default
+            assert_eq!(run(2, optimize), 14);
+            assert_eq!(run(-1, optimize), 14);
         }
-    }
 
-    /// Push a number to stack
-    fn ins_load_val(&mut self, number: i32) -> anyhow::Result<()> {
-        self.push_stack(number);
-        Ok(())
+        assert!(BciVm::load("MAIN:\nSWITCH\nHALT 0").is_err());
     }
 
-    /// Add two numbers
-    fn ins_add(&mut self) -> anyhow::Result<()> {
-        if self.sp < 1 {
-            return Err(anyhow!("Fatal: stack is smaller than 2"));
-        }
+    #[test]
+    fn read_file_all() {
+        let dir = TestDir::new("read_file_all");
+        let path = dir.join("file.txt");
+        fs::write(&path, "first line\nsecond line").unwrap();
+        let path = path.to_str().unwrap();
 
-        let lhs = self.pop_stack()?;
-        let rhs = self.pop_stack()?;
+        let program = format!(
+            "MAIN:\nPUSH_STR '{path}'\nCALL READ_FILE_ALL\nWRITE_VAR 'ok'\n\
+             WRITE_STR_VAR 'content'\nREAD_STR_VAR 'content'\nPUSH_STR 'first line'\nCMP_STR\n\
+             WRITE_VAR 'same_as_first'\nREAD_STR_VAR 'content'\nLOAD_VAL 11\nLOAD_VAL 22\n\
+             CALL STR_SLICE\nPUSH_STR 'second line'\nCMP_STR\nWRITE_VAR 'same_as_second'\n\
+             PUSH_STR '{path}.missing'\nCALL READ_FILE_ALL\nHALT 0"
+        );
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_var("ok"), Some(1));
+        assert_eq!(vm.get_var("same_as_first"), Some(1));
+        assert_eq!(vm.get_var("same_as_second"), Some(0));
+        // A missing file only pushes the flag
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.sp, -1);
 
-        self.push_stack(lhs + rhs);
+        // A file that does not fit in the stack fails the same way
+        let path = dir.join("huge.txt");
+        fs::write(&path, "a".repeat(CELL_SIZE * 1000)).unwrap();
+        let program = format!(
+            "MAIN:\nPUSH_STR '{}'\nCALL READ_FILE_ALL\nHALT 0",
+            path.display()
+        );
+        let mut vm = BciVm::load(&program).unwrap();
+        vm.run().unwrap();
 
-        Ok(())
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.sp, -1);
     }
 
-    /// Multiply two numbers
-    fn ins_mul(&mut self) -> anyhow::Result<()> {
-        if self.sp < 1 {
-            return Err(anyhow!("Fatal: stack size is smaller than 2"));
-        }
+    #[test]
+    fn call_rel() {
+        let program = r"
+            SQUARE:
+            WRITE_VAR 'x'
+            READ_VAR 'x'
+            READ_VAR 'x'
+            MUL
+            RETURN_VALUE
 
-        let lhs = self.pop_stack()?;
-        let rhs = self.pop_stack()?;
+            MAIN:
+            LOAD_VAL 7
+            CALL SQUARE
+            LOAD_VAL 7
+            CALL_REL 11
+            STACK_SIZE
+            HALT 0
+        ";
+        let run = |bytecode: Bytecode| {
+            let mut vm = BciVm::from_bytecode(bytecode);
+            vm.run().unwrap();
+            vm
+        };
 
-        self.push_stack(lhs * rhs);
+        let vm = run(Parser::new(program).parse().unwrap());
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[49, 49, 2]);
+        assert_eq!(vm.frame_stack.len(), 1);
 
-        Ok(())
-    }
+        // The offset is rewritten along with the addresses of the functions
+        let vm = run(Parser::new(program).parse().unwrap().optimize());
+        assert_eq!(&vm.stack[..=vm.sp as usize], &[49, 49, 2]);
 
-    /// Push a string on stack
-    ///
-    /// To use the least amount of memory, instead of putting 1-byte characters to per memory
-    /// cell, it puts 4 character to a memory cell. We can also take advantage of cheap (but dangerous)
-    /// copies like this. Because we just map the byte array to the stack as is.
-    ///
-    /// Eg.
-    /// Suppose that we have 4, 4-byte wide memory cells from bottom to top respectively.
-    /// |   0   |   0   |   0   |   0   |
-    /// PUSH_STR 'hello world!' puts the data and the size of the string.
-    /// |  h e l l  |  o _ w o  |  r l d !  |  12  |
-    fn ins_push_str<'b>(&mut self, s: &'b str) -> anyhow::Result<()> {
-        self.sp += 1;
+        assert!(BciVm::load("MAIN:\nCALL_REL 0").unwrap().run().is_err());
+        assert!(BciVm::load("MAIN:\nCALL_REL -5").unwrap().run().is_err());
+    }
 
-        if s.len() >= self.sp as usize + self.stack.len() {
-            return Err(anyhow!("fatal: out of memory"));
-        }
+    #[test]
+    fn profile() {
+        let program =
+            "MAIN:\nLOAD_VAL 3\nWRITE_VAR 'i'\nREAD_VAR 'i'\nDECR\nTEE_VAR 'i'\nJNZ 3\nHALT 0";
+        let run = |profile: Profile| {
+            let buf = SharedBuf::default();
+            let mut vm = BciVm::load(program).unwrap();
+            vm.set_writer(Box::new(buf.clone()));
+            vm.set_profile(profile);
+            vm.run().unwrap();
+            buf.contents()
+        };
 
-        // Copy the string to the stack as is
-        let src = s.as_ptr();
-        unsafe {
-            let dest = self.stack.as_ptr().offset(self.sp);
-            std::ptr::copy_nonoverlapping(src, dest as *mut u8, s.len());
-        }
+        assert_eq!(run(Profile::Off), "");
+        assert_eq!(
+            run(Profile::Counts),
+            ">>>>> profile: 17 instructions\n\
+             >>>>>   DECR: 3\n\
+             >>>>>   JNZ: 3\n\
+             >>>>>   READ_VAR: 3\n\
+             >>>>>   TEE_VAR: 3\n\
+             >>>>>   CALL: 1\n\
+             >>>>>   HALT: 1\n\
+             >>>>>   LOAD_VAL: 1\n\
+             >>>>>   NOP: 1\n\
+             >>>>>   WRITE_VAR: 1\n"
+        );
 
-        // Since 4 character fits in a memory cell, divide the string length by 4
-        self.sp += s.len() as isize / 4;
+        let report = run(Profile::Timed);
+        assert!(report.starts_with(">>>>> profile: 17 instructions\n"));
+        assert!(report.lines().last().unwrap().starts_with(">>>>> time: "));
+    }
 
-        // Finally the string length
-        self.push_stack(s.len() as i32);
+    #[test]
+    fn constants() {
+        let with_const = Parser::new("CONST LIMIT 10\nCONST DEFAULT LIMIT\nMAIN:\nLOAD_VAL LIMIT\nREAD_VAR_OR 'x' DEFAULT\nSWITCH LIMIT 1\nHALT 0")
+            .parse()
+            .unwrap();
+        let inlined =
+            Parser::new("\n\nMAIN:\nLOAD_VAL 10\nREAD_VAR_OR 'x' 10\nSWITCH 10 1\nHALT 0")
+                .parse()
+                .unwrap();
+        assert_eq!(with_const.instructions, inlined.instructions);
 
-        Ok(())
+        let err = Parser::new("MAIN:\nREAD_VAR_OR 'x' LIMIT\nHALT 0")
+            .parse()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Expected Number, got Some(Name(\"LIMIT\"))"
+        );
+        let err = Parser::new("CONST A 1\nCONST A 2\nMAIN:\nHALT 0")
+            .parse()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Constant A is already defined.");
     }
 
-    /// Pops a string from stack. Discards the poped string. This is mainly for internal use.
-    fn ins_pop_str(&mut self) -> anyhow::Result<String> {
-        let str_len = self.pop_stack()?;
+    #[test]
+    fn undefined_var_is_zero() {
+        let program = "MAIN:\nREAD_VAR 'missing'\nPRINT\nHALT 0";
 
-        if str_len < 0 {
-            return Err(anyhow!("fatal: negative strlen."));
-        }
+        let mut vm = BciVm::load(program).unwrap();
+        let err = vm.run().err().unwrap();
+        assert_eq!(
+            err.downcast::<RuntimeError>().unwrap().error.to_string(),
+            "Variable 'missing' does not exist."
+        );
 
-        if str_len == 0 {
-            return Ok(String::new());
-        }
+        let buf = SharedBuf::default();
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_writer(Box::new(buf.clone()));
+        vm.set_undefined_var_is_zero(true);
+        vm.run().unwrap();
+        assert_eq!(buf.contents(), ">>>>> 0\n");
+    }
 
-        let mem_len = str_len / 4 + 1;
+    #[test]
+    fn str_find() {
+        let find = |haystack: &str, needle: &str| {
+            let program = format!(
+                "MAIN:\nPUSH_STR '{}'\nPUSH_STR '{}'\nCALL STR_FIND\nHALT 0",
+                haystack, needle
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run().unwrap();
+            let index = vm.pop_stack().unwrap();
+            // Both strings are consumed
+            assert_eq!(vm.sp, -1);
+            index
+        };
 
-        if self.sp as i32 - mem_len + 1 < 0 {
-            return Err(anyhow!("fatal: not enough stack."));
-        }
+        assert_eq!(find("hello world", "o"), 4);
+        assert_eq!(find("hello world", "world"), 6);
+        assert_eq!(find("hello world", "xyz"), -1);
+        assert_eq!(find("hello", "hello world"), -1);
 
-        self.sp -= mem_len as isize;
+        // Empty literals are not supported, so the empty needle is sliced from another string
+        let program = "MAIN:\nPUSH_STR 'hello'\nPUSH_STR 'x'\nLOAD_VAL 0\nLOAD_VAL 0\nCALL STR_SLICE\nCALL STR_FIND\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+    }
 
-        let mut out_str = String::with_capacity(str_len as usize);
-        let str_ptr = self.stack.as_ptr() as *const u8;
-        unsafe {
-            for i in 0..str_len {
-                out_str.push(*str_ptr.offset((self.sp + 1) * 4 + i as isize) as char);
+    #[test]
+    fn print_prefix() {
+        let program = "MAIN:\nLOAD_VAL 42\nPRINT\nLOAD_VAL 255\nCALL PRINT_HEX\nPUSH_STR 'hi'\nCALL PRINT_STR\nHALT 0";
+        let run = |prefix: Option<&str>| {
+            let buf = SharedBuf::default();
+            let mut vm = BciVm::load(program).unwrap();
+            vm.set_writer(Box::new(buf.clone()));
+            if let Some(prefix) = prefix {
+                vm.set_print_prefix(prefix.to_string());
             }
-        }
+            vm.run().unwrap();
+            buf.contents()
+        };
 
-        Ok(out_str)
+        assert_eq!(run(None), ">>>>> 42\n>>>>> 0xff\n>>>>> hi\n");
+        assert_eq!(run(Some("")), "42\n0xff\nhi\n");
+        assert_eq!(run(Some("out: ")), "out: 42\nout: 0xff\nout: hi\n");
+
+        // Diagnostics of the VM use the prefix as well
+        let buf = SharedBuf::default();
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 1\nLOAD_VAL 2\nCALL DUMP_STACK\nHALT 0").unwrap();
+        vm.set_writer(Box::new(buf.clone()));
+        vm.set_print_prefix(String::new());
+        vm.run().unwrap();
+        assert_eq!(buf.contents(), "stack: [1, 2]\n");
     }
 
-    fn pop_stack(&mut self) -> anyhow::Result<i32> {
-        if self.sp < 0 {
-            return Err(anyhow!("Fatal: stack is empty."));
-        }
+    #[test]
+    fn load_ret() {
+        let program = r"
+            VALUE:
+            LOAD_VAL 7
+            RETURN_VALUE
 
-        self.sp -= 1;
-        Ok(self.stack[(self.sp + 1) as usize])
-    }
+            NO_VALUE:
+            RETURN
 
-    fn push_stack(&mut self, data: i32) {
-        self.sp += 1;
-        self.stack[self.sp as usize] = data;
+            MAIN:
+            CALL VALUE
+            DROP_N 1
+            LOAD_RET
+            CALL NO_VALUE
+            LOAD_RET
+            HALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+
+        // `NO_VALUE` did not push anything, so only the flag is on stack
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.pop_stack().unwrap(), 7);
+        assert_eq!(vm.sp, -1);
+
+        // Nothing has returned yet
+        let mut vm = BciVm::load("MAIN:\nLOAD_RET\nHALT 0").unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.pop_stack().unwrap(), 0);
+        assert_eq!(vm.sp, -1);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::bytecode::Instruction;
-    use std::mem::discriminant;
+    #[test]
+    fn parse_library() {
+        let library = "DOUBLE:\nLOAD_VAL 2\nMUL\nRETURN_VALUE\n\nSQUARE:\nREAD_VAR 'x'\nREAD_VAR 'x'\nMUL\nRETURN_VALUE";
+        assert_eq!(
+            Parser::new(library).parse().err().unwrap().to_string(),
+            "Could not find the entry point(MAIN)."
+        );
 
-    use super::*;
+        let bytecode = Parser::new(library).parse_library().unwrap();
+        assert_eq!(bytecode.function_names(), ["DOUBLE", "SQUARE"]);
 
-    fn run_until_instruction<'a>(
-        program: &'a str,
-        instruction: Instruction,
-    ) -> anyhow::Result<BciVm<'a>> {
-        let mut vm = BciVm::load(program).unwrap();
-        loop {
-            vm.next_instruction().unwrap();
-            if discriminant(&vm.bytecode.instructions[vm.ip]) == discriminant(&instruction) {
-                vm.next_instruction().unwrap();
-                return Ok(vm);
-            } else if vm.halt.is_some() {
-                return Err(anyhow!("process is halted unexpectedly"));
-            }
-        }
+        // Only the defined functions, without the call of `MAIN`
+        assert_eq!(bytecode.fn_table["DOUBLE"].ptr, 0);
+        assert_eq!(bytecode.fn_table["SQUARE"].ptr, 5);
+        assert_eq!(bytecode.instructions.len(), 10);
+        assert_eq!(bytecode.instructions[1], Instruction::LoadVal(2));
     }
 
     #[test]
-    fn load() {
-        let program = "MAIN:\nLOAD_VAL 10\nLOAD_VAL 20\nHALT 0";
-        let vm = run_until_instruction(program, Instruction::Halt(0)).unwrap();
+    fn link() {
+        let library = || {
+            Parser::new(
+                "DOUBLE:\nLOAD_VAL 2\nMUL\nRETURN_VALUE\n\nGREET:\nPUSH_STR 'lib'\nCALL PRINT_STR\nLOAD_CONST_STR 0\nCALL PRINT_STR\nRETURN",
+            )
+            .parse_library()
+            .unwrap()
+        };
+        let main = || {
+            Parser::new(
+                "MAIN:\nPUSH_STR 'main'\nCALL PRINT_STR\nLOAD_VAL 3\nCALL DOUBLE\nPRINT\nCALL GREET\nHALT 0",
+            )
+            .parse()
+            .unwrap()
+        };
 
-        let stack = [10, 20];
-        assert_eq!(&vm.stack[0..2], &stack);
-        assert_eq!(vm.sp, 1);
+        let linked = Bytecode::link(vec![main(), library()]).unwrap();
+        assert_eq!(linked.function_names(), ["DOUBLE", "GREET", "MAIN"]);
+        let double = linked.fn_table["DOUBLE"].ptr;
+        assert_eq!(linked.instructions[double + 1], Instruction::LoadVal(2));
+        // 'lib' comes after the strings of `MAIN`
+        assert_eq!(&*linked.str_pool[1], "lib");
+
+        let buf = SharedBuf::default();
+        let mut vm = BciVm::from_bytecode(linked);
+        vm.set_writer(Box::new(buf.clone()));
+        vm.run().unwrap();
+        assert_eq!(vm.halt, Some(0));
+        assert_eq!(
+            buf.contents(),
+            ">>>>> main\n>>>>> 6\n>>>>> lib\n>>>>> lib\n"
+        );
+
+        let err = Bytecode::link(vec![library()]).err().unwrap();
+        assert_eq!(err.to_string(), "Could not find the entry point(MAIN).");
+
+        let duplicate = Parser::new("DOUBLE:\nRETURN").parse_library().unwrap();
+        let err = Bytecode::link(vec![main(), library(), duplicate])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Function DOUBLE is already defined.");
     }
 
     #[test]
-    fn read_write() {
-        let program = "MAIN:\nLOAD_VAL 10\nWRITE_VAR 'x'\nLOAD_VAL 20\nREAD_VAR 'x'\n";
-        let vm = run_until_instruction(program, Instruction::ReadVar("")).unwrap();
+    fn upvars() {
+        let program = r"
+            INCREMENT:
+            READ_UPVAR 'counter'
+            INCR
+            WRITE_UPVAR 'counter'
+            LOAD_VAL 1
+            WRITE_UPVAR 'created'
+            RETURN
 
-        assert_eq!(vm.stack[vm.sp as usize], 10);
+            MAIN:
+            LOAD_VAL 5
+            WRITE_VAR 'counter'
+            CALL INCREMENT
+            CALL INCREMENT
+            READ_VAR 'counter'
+            READ_VAR 'created'
+            HALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.pop_stack().unwrap(), 7);
+
+        let error = |program: &str| {
+            let mut vm = BciVm::load(program).unwrap();
+            let err = vm.run().err().unwrap();
+            err.downcast::<RuntimeError>().unwrap().error.to_string()
+        };
         assert_eq!(
-            vm.frame_stack[vm.fp as usize].local_vars.get("x"),
-            Some(&10)
+            error("MAIN:\nLOAD_VAL 1\nWRITE_UPVAR 'x'\nHALT 0"),
+            "There is no caller frame."
+        );
+        assert_eq!(
+            error("F:\nREAD_UPVAR 'x'\nRETURN\nMAIN:\nCALL F\nHALT 0"),
+            "Variable 'x' does not exist in the caller frame."
         );
-        assert_eq!(vm.sp, 1);
     }
 
     #[test]
-    fn arithmetic() {
-        let base_program = "MAIN:\nLOAD_VAL 6\nLOAD_VAL 4\n";
+    fn format_int() {
+        let format = |number: i64, width: i64, zero_pad: i64| {
+            let program = format!(
+                "MAIN:\nLOAD_VAL {}\nLOAD_VAL {}\nLOAD_VAL {}\nCALL FORMAT_INT\nHALT 0",
+                number, width, zero_pad
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run()?;
+            vm.ins_pop_str()
+        };
 
-        let add_prog = base_program.to_string() + "ADD";
-        let vm = run_until_instruction(&add_prog, Instruction::Add).unwrap();
-        assert_eq!(vm.stack[vm.sp as usize], 10);
+        assert_eq!(format(5, 3, 0).unwrap(), "  5");
+        assert_eq!(format(5, 3, 1).unwrap(), "005");
+        assert_eq!(format(-5, 3, 1).unwrap(), "-05");
+        assert_eq!(format(12345, 3, 0).unwrap(), "12345");
+        assert_eq!(format(7, 0, 0).unwrap(), "7");
+        assert_eq!(
+            format(5, -1, 0)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .error
+                .to_string(),
+            "FORMAT_INT width -1 is out of range"
+        );
+    }
 
-        let mul_prog = base_program.to_string() + "MUL";
-        let vm = run_until_instruction(&mul_prog, Instruction::Mul).unwrap();
-        assert_eq!(vm.stack[vm.sp as usize], 24);
+    #[test]
+    fn to_json() {
+        let bytecode =
+            Parser::new("MAIN:\nPUSH_STR 'say \"hi\"'\nREAD_VAR_OR 'x' 3\nSWITCH 1 -2\nHALT 0")
+                .parse()
+                .unwrap();
+        assert_eq!(
+            bytecode.to_json(),
+            "{\"instructions\":[\
+             {\"addr\":0,\"op\":\"CALL\",\"args\":[\"MAIN\"],\"line\":0},\
+             {\"addr\":1,\"op\":\"EXIT\",\"args\":[],\"line\":0},\
+             {\"addr\":2,\"op\":\"NOP\",\"args\":[],\"line\":1},\
+             {\"addr\":3,\"op\":\"PUSH_STR\",\"args\":[\"say \\\"hi\\\"\"],\"line\":2},\
+             {\"addr\":4,\"op\":\"READ_VAR_OR\",\"args\":[\"x\",3],\"line\":3},\
+             {\"addr\":5,\"op\":\"SWITCH\",\"args\":[1,-2],\"line\":4},\
+             {\"addr\":6,\"op\":\"HALT\",\"args\":[0],\"line\":5}],\
+             \"functions\":[{\"name\":\"MAIN\",\"ptr\":2}],\
+             \"str_pool\":[\"say \\\"hi\\\"\"]}"
+        );
     }
 
     #[test]
-    fn jmp() {
-        let program = "MAIN:\nNOP\nLOAD_VAL 1\nJMP 2";
-        let vm = run_until_instruction(program, Instruction::Jmp(0)).unwrap();
-        assert_eq!(vm.bytecode.instructions[vm.ip], Instruction::Nop);
+    fn parse_reader() {
+        let program = r"
+            # A comment
+            CONST LIMIT 3
+            GREET:
+            PUSH_STR 'hi'; CALL PRINT_STR
+            RETURN
 
-        let program = "MAIN:\nJMP -2\nLOAD_VAL 1\nNOP";
-        let vm = run_until_instruction(program, Instruction::Jmp(0)).unwrap();
-        assert_eq!(vm.bytecode.instructions[vm.ip], Instruction::Nop);
+            MAIN:
+            PUSH_STR 'hi'
+            POP_STR
+            LOAD_VAL LIMIT
+            CALL GREET
+            DECR; TEE_VAR 'i'
+            JNZ 2
+            HALT 0";
+        let from_str = Parser::new(program).parse().unwrap();
+        let from_reader = Parser::parse_reader(program.as_bytes()).unwrap();
+
+        assert_eq!(from_reader.instructions, from_str.instructions);
+        assert_eq!(from_reader.source_lines, from_str.source_lines);
+        assert_eq!(from_reader.str_pool, from_str.str_pool);
+        assert_eq!(from_reader.function_names(), from_str.function_names());
+        for name in from_str.function_names() {
+            assert_eq!(from_reader.fn_table[name].ptr, from_str.fn_table[name].ptr);
+        }
+
+        // Lines that end with '\r' are read along with the next one
+        let program = program.replace('\n', "\r");
+        let from_reader = Parser::parse_reader(program.replacen('\r', "\n", 3).as_bytes());
+        assert_eq!(from_reader.unwrap().source_lines, from_str.source_lines);
+
+        assert!(Parser::parse_reader("GREET:\nRETURN".as_bytes()).is_err());
     }
 
     #[test]
-    fn push_str() {
-        let inp_str = "hello world";
-        let program = format!("MAIN:\nPUSH_STR '{}'\nHALT 0", inp_str);
+    fn has_extension() {
+        let has_extension = |path: &str, extension: &str| {
+            let program = format!(
+                "MAIN:\nPUSH_STR '{}'\nPUSH_STR '{}'\nCALL HAS_EXTENSION\nHALT 0",
+                path, extension
+            );
+            let mut vm = BciVm::load(&program).unwrap();
+            vm.run().unwrap();
+            assert_eq!(vm.sp, 0);
+            vm.pop_stack().unwrap()
+        };
 
-        let mut vm = BciVm::load(&program).unwrap();
+        assert_eq!(has_extension("notes.txt", "txt"), 1);
+        assert_eq!(has_extension("dir/NOTES.TXT", "txt"), 1);
+        assert_eq!(has_extension("notes.txt", ".Txt"), 1);
+        assert_eq!(has_extension("archive.tar.gz", "tar.gz"), 1);
+        assert_eq!(has_extension("archive.tar.gz", "gz"), 1);
+
+        assert_eq!(has_extension("notes.txt", "md"), 0);
+        assert_eq!(has_extension("notestxt", "txt"), 0);
+        assert_eq!(has_extension("notes.ttxt", "txt"), 0);
+        assert_eq!(has_extension("txt", "txt"), 0);
+        assert_eq!(has_extension("dir.txt/notes", "txt"), 0);
+        assert_eq!(has_extension("notes.", "."), 0);
+    }
+
+    #[test]
+    fn string_canaries() {
+        let program = "MAIN:\nPUSH_STR 'hello world'\nPUSH_STR 'a'\nCMP3\nPUSH_STR 'x'\nLOAD_VAL 1\nLOAD_VAL 1\nCALL STR_SLICE\nHALT 0";
+        let mut vm = BciVm::load(program).unwrap();
+        vm.set_string_canaries(true);
         vm.run().unwrap();
+        assert_eq!(vm.ins_pop_str().unwrap(), "");
+        assert_eq!(vm.pop_stack().unwrap(), 1);
+        assert_eq!(vm.sp, -1);
 
-        // Check if string contents are equal
-        let stack_ptr = vm.stack.as_ptr() as *const u8;
-        for i in 0..inp_str.len() {
-            unsafe {
-                assert_eq!(inp_str.as_bytes()[i], *stack_ptr.offset(i as isize));
-            }
-        }
+        let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+        vm.set_string_canaries(true);
+        vm.push_stack(7).unwrap();
+        vm.ins_push_str("hello world").unwrap();
+        // The canary, data cells and the length cell
+        assert_eq!(vm.sp, str_data_cells(11) + 2);
 
-        // Check the size
-        assert_eq!(vm.stack[inp_str.len() / 4 + 1] as usize, inp_str.len());
+        // A length that ends in another cell misses the canary
+        vm.stack[vm.sp as usize] = 16;
+        let err = vm.ins_pop_str().err().unwrap();
+        assert_eq!(err.to_string(), "fatal: string canary is corrupted.");
+
+        // Without canaries, the same corruption is read as a string silently
+        let mut vm = BciVm::load("MAIN:\nHALT 0").unwrap();
+        vm.push_stack(0).unwrap();
+        vm.ins_push_str("hello world").unwrap();
+        vm.stack[vm.sp as usize] = 16;
+        assert!(vm.ins_pop_str().is_ok());
     }
 
     #[test]
-    fn pop_str() {
-        let program = "MAIN:\nPUSH_STR 'hello world'\nPOP_STR\nHALT 0";
-        let mut vm = BciVm::load(program).unwrap();
+    fn exit_code() {
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 1\nHALT 5").unwrap();
+        assert_eq!(vm.exit_code(), None);
+        assert!(!vm.is_halted());
+
         vm.run().unwrap();
+        assert_eq!(vm.exit_code(), Some(5));
+        assert!(vm.is_halted());
 
-        // Stack should be empty
-        assert_eq!(vm.sp, -1);
+        // A failed run does not halt
+        let mut vm = BciVm::load("MAIN:\nADD\nHALT 5").unwrap();
+        assert!(vm.run().is_err());
+        assert_eq!(vm.exit_code(), None);
+        assert!(!vm.is_halted());
     }
 
     #[test]
-    fn cmp() {
-        let program = "MAIN:\nLOAD_VAL 1\nLOAD_VAL 1\nCMP";
-        let vm = run_until_instruction(program, Instruction::Cmp).unwrap();
-        assert_eq!(vm.stack[vm.sp as usize], 0);
-        assert_eq!(vm.sp, 0);
+    fn random() {
+        let program = "MAIN:\nLOAD_VAL 42\nCALL SEED_RANDOM\nLOAD_VAL 100\nCALL RANDOM\nLOAD_VAL 100\nCALL RANDOM\nLOAD_VAL 100\nCALL RANDOM\nLOAD_VAL 42\nCALL SEED_RANDOM\nLOAD_VAL 100\nCALL RANDOM\nLOAD_VAL 100\nCALL RANDOM\nLOAD_VAL 100\nCALL RANDOM\nHALT 0";
+        let sequence = |program: &str| {
+            let mut vm = BciVm::load(program).unwrap();
+            vm.run().unwrap();
+            (0..=vm.sp)
+                .map(|i| vm.stack[i as usize])
+                .collect::<Vec<_>>()
+        };
+
+        let numbers = sequence(program);
+        assert!(numbers.iter().all(|n| (0..100).contains(n)));
+        // Reseeding repeats the sequence, and so does another run
+        assert_eq!(numbers[..3], numbers[3..]);
+        assert_eq!(sequence(program), numbers);
+        // Another seed gives another sequence
+        assert_ne!(sequence(&program.replace("42", "7")), numbers);
+
+        let mut vm = BciVm::load("MAIN:\nLOAD_VAL 0\nCALL RANDOM\nHALT 0").unwrap();
+        let err = vm.run().err().unwrap();
+        assert_eq!(
+            err.downcast::<RuntimeError>().unwrap().error.to_string(),
+            "RANDOM expects a positive bound, got 0"
+        );
     }
 
     #[test]
-    fn cmp_str() {
-        let program = "MAIN:\nPUSH_STR 'hello'\nPUSH_STR 'hello'\nCMP_STR";
-        let vm = run_until_instruction(program, Instruction::CmpStr).unwrap();
-        assert_eq!(vm.stack[vm.sp as usize], 0);
-        assert_eq!(vm.sp, 0);
+    fn pure_functions() {
+        let program = "SQUARE: PURE\nWRITE_VAR 'x'\nREAD_VAR 'x'\nREAD_VAR 'x'\nMUL\nRETURN_VALUE\nLOG:\nCALL PRINT\nRETURN\nMAIN:\nLOAD_VAL 3\nCALL SQUARE\nCALL LOG\nHALT 0";
+        let bytecode = Parser::new(program).parse().unwrap();
+        assert!(bytecode.fn_table["SQUARE"].pure);
+        assert!(!bytecode.fn_table["LOG"].pure);
+        assert!(!bytecode.fn_table["MAIN"].pure);
+
+        // The annotation does not change how the program runs
+        let (vm, output) = run_with_output(program);
+        assert_eq!(vm.halt, Some(0));
+        assert_eq!(output, ">>>>> 9\n");
+
+        let err = Parser::new("MAIN: FAST\nHALT 0").parse().err().unwrap();
+        assert_eq!(err.to_string(), "Unknown function attribute 'FAST'");
     }
 }